@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+enum State {
+    Closed,
+    Open(Instant),
+    HalfOpen { probing: bool },
+}
+
+// Circuit breaker of a single service. After `threshold` consecutive failed
+// requests the circuit opens and the service is ejected for `cooldown`. Then a
+// single probe request is let through (half-open): if it succeeds the circuit
+// closes again, otherwise the service is ejected for another cool-down period.
+pub struct Breaker {
+    state: State,
+    failures: u32,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl Breaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Breaker {
+            state: State::Closed,
+            failures: 0,
+            threshold,
+            cooldown,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        match self.state {
+            State::Closed => true,
+            State::Open(until) => Instant::now() >= until,
+            State::HalfOpen { probing } => !probing,
+        }
+    }
+
+    // Checks whether a request can be sent to the service and, once the cool-down
+    // is over, lets the caller probe the half-open circuit. Both happen under the
+    // same lock, so only one request gets to probe.
+    pub fn try_acquire(&mut self) -> bool {
+        match self.state {
+            State::Closed => true,
+            State::Open(until) if Instant::now() >= until => {
+                self.state = State::HalfOpen { probing: true };
+                true
+            }
+            State::Open(_) => false,
+            State::HalfOpen { probing } => {
+                self.state = State::HalfOpen { probing: true };
+                !probing
+            }
+        }
+    }
+
+    // Returns true when the failure opens the circuit.
+    pub fn record_failure(&mut self) -> bool {
+        match self.state {
+            State::Closed => {
+                self.failures += 1;
+                if self.failures >= self.threshold {
+                    self.state = State::Open(Instant::now() + self.cooldown);
+                    true
+                } else {
+                    false
+                }
+            }
+            State::HalfOpen { .. } => {
+                self.state = State::Open(Instant::now() + self.cooldown);
+                true
+            }
+            State::Open(_) => false,
+        }
+    }
+
+    // Returns true when the success closes the circuit. Only the probe of a
+    // half-open circuit can close it: requests sent before the circuit opened may
+    // still succeed while it is open, and those are ignored.
+    pub fn record_success(&mut self) -> bool {
+        match self.state {
+            State::Closed => {
+                self.failures = 0;
+                false
+            }
+            State::HalfOpen { .. } => {
+                self.failures = 0;
+                self.state = State::Closed;
+                true
+            }
+            State::Open(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_request_probes_a_half_open_circuit() {
+        let mut breaker = Breaker::new(2, Duration::ZERO);
+        assert!(!breaker.record_failure());
+        assert!(breaker.record_failure());
+
+        assert!(breaker.is_available());
+        assert!(breaker.try_acquire());
+        assert!(!breaker.is_available());
+        assert!(!breaker.try_acquire());
+
+        assert!(breaker.record_success());
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn successes_do_not_close_an_open_circuit() {
+        let mut breaker = Breaker::new(1, Duration::from_secs(60));
+        assert!(breaker.record_failure());
+
+        assert!(!breaker.record_success());
+        assert!(!breaker.is_available());
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn successes_reset_the_failure_count() {
+        let mut breaker = Breaker::new(2, Duration::from_secs(60));
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_success());
+        assert!(!breaker.record_failure());
+        assert!(breaker.is_available());
+    }
+
+    #[test]
+    fn open_circuit_waits_for_the_cooldown() {
+        let mut breaker = Breaker::new(1, Duration::from_secs(60));
+        assert!(breaker.record_failure());
+
+        assert!(!breaker.is_available());
+        assert!(!breaker.try_acquire());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+
+use crate::balancer::health::ServiceHealth;
+use crate::balancer::{Balancer, RequestContext};
+use crate::opts::{HashKey, Service};
+
+static VNODES_PER_WEIGHT: u32 = 160;
+
+// Each service is placed on a hash ring as many virtual nodes (proportional to its
+// weight) and a request goes to the first node found clockwise from the hash of
+// its key. Adding or removing a service only remaps the keys that fall next to
+// that service's nodes.
+pub struct ConsistentHash {
+    ring: BTreeMap<u64, usize>,
+    services: Vec<Service>,
+    key: HashKey,
+    health: ServiceHealth,
+}
+
+impl ConsistentHash {
+    pub fn new(services: Vec<Service>, key: HashKey, health: ServiceHealth) -> Self {
+        let mut ring = BTreeMap::new();
+
+        for (i, service) in services.iter().enumerate() {
+            for vnode in 0..service.weight() * VNODES_PER_WEIGHT {
+                let point = hash(format!("{}#{}", service.host(), vnode).as_bytes());
+                ring.insert(point, i);
+            }
+        }
+
+        ConsistentHash {
+            ring,
+            services,
+            key,
+            health,
+        }
+    }
+
+    fn request_key(&self, req: &RequestContext) -> Option<String> {
+        let client_ip = || req.client_ip.map(|ip| ip.to_string());
+
+        match &self.key {
+            HashKey::ClientIp => client_ip(),
+            HashKey::Cookie { name } => req
+                .header
+                .headers
+                .get("cookie")
+                .and_then(|cookies| find_cookie(cookies, name))
+                .or_else(client_ip),
+            HashKey::Header { name } => req
+                .header
+                .headers
+                .get(name.to_lowercase().as_str())
+                .cloned()
+                .or_else(client_ip),
+        }
+    }
+}
+
+impl Balancer for ConsistentHash {
+    fn pick(&self, req: &RequestContext) -> Option<Service> {
+        let point = hash(self.request_key(req).unwrap_or_default().as_bytes());
+
+        // Keys of a service that is down (or already failed the request) move to the
+        // next service on the ring.
+        self.ring
+            .range(point..)
+            .chain(self.ring.range(..point))
+            .map(|(_, i)| &self.services[*i])
+            .find(|service| !req.is_excluded(service) && self.health.try_acquire(service))
+            .cloned()
+    }
+}
+
+fn find_cookie(cookies: &str, name: &str) -> Option<String> {
+    cookies.split(';').find_map(|cookie| {
+        let (key, val) = cookie.split_once('=')?;
+        if key.trim() == name {
+            Some(val.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+// FNV-1a followed by the murmur3 finalizer, which spreads the virtual nodes of
+// similar host names evenly over the ring. Unlike std's `DefaultHasher` it is
+// stable across builds, so every proxy instance maps a key to the same service.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::Headers;
+    use crate::http::request::{Method, RequestHeader, RequestLine};
+
+    fn service(port: u16) -> Service {
+        Service {
+            addr: "127.0.0.1".to_string(),
+            port,
+            weight: None,
+        }
+    }
+
+    fn balancer(ports: &[u16]) -> ConsistentHash {
+        ConsistentHash::new(
+            ports.iter().map(|p| service(*p)).collect(),
+            HashKey::Header {
+                name: "X-User".to_string(),
+            },
+            ServiceHealth::new(None),
+        )
+    }
+
+    fn pick_port(balancer: &ConsistentHash, user: usize, excluded: &[Service]) -> u16 {
+        let mut headers = Headers::new();
+        headers.insert("x-user".to_string(), format!("user-{user}"));
+        let header = RequestHeader {
+            metadata: RequestLine {
+                method: Method::Get,
+                uri: "/".to_string(),
+                version: "HTTP/1.1".to_string(),
+            },
+            headers,
+        };
+        let req = RequestContext {
+            client_ip: None,
+            header: &header,
+            excluded,
+        };
+
+        balancer.pick(&req).unwrap().port
+    }
+
+    #[test]
+    fn adding_a_service_only_remaps_keys_to_it() {
+        let before = balancer(&[1, 2, 3]);
+        let after = balancer(&[1, 2, 3, 4]);
+
+        let mut moved = 0;
+        for user in 0..1000 {
+            let (old, new) = (pick_port(&before, user, &[]), pick_port(&after, user, &[]));
+            if old != new {
+                assert_eq!(new, 4);
+                moved += 1;
+            }
+        }
+        // About a quarter of the keys should move to the new service.
+        assert!((150..350).contains(&moved), "{moved} keys moved");
+    }
+
+    #[test]
+    fn keys_of_an_excluded_service_move_to_the_others() {
+        let balancer = balancer(&[1, 2, 3]);
+
+        for user in 0..1000 {
+            let port = pick_port(&balancer, user, &[]);
+            let fallback = pick_port(&balancer, user, &[service(3)]);
+            if port == 3 {
+                assert_ne!(fallback, 3);
+            } else {
+                assert_eq!(fallback, port);
+            }
+        }
+    }
+
+    #[test]
+    fn cookies_are_found_by_name() {
+        let cookies = "theme=dark; session=abc123 ;lang=en";
+        assert_eq!(find_cookie(cookies, "session"), Some("abc123".to_string()));
+        assert_eq!(find_cookie(cookies, "lang"), Some("en".to_string()));
+        assert_eq!(find_cookie(cookies, "user"), None);
+    }
+}
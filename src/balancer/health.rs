@@ -0,0 +1,189 @@
+use anyhow::{Context, Error, Result};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::balancer::circuit::Breaker;
+use crate::http::request::Method;
+use crate::http::response::Response;
+use crate::opts::{CircuitBreaker, HealthCheck, Service};
+
+// Availability of each service as seen by the balancers. A service is available
+// when the active health checks consider it up (it is until a check says
+// otherwise) and its circuit breaker, if enabled, is not open.
+#[derive(Clone, Default)]
+pub struct ServiceHealth {
+    down: Arc<RwLock<HashMap<String, bool>>>,
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
+    breaker_opts: Option<CircuitBreaker>,
+}
+
+impl ServiceHealth {
+    pub fn new(breaker_opts: Option<CircuitBreaker>) -> Self {
+        ServiceHealth {
+            breaker_opts,
+            ..ServiceHealth::default()
+        }
+    }
+
+    pub fn is_available(&self, service: &Service) -> bool {
+        self.is_up(service)
+            && self
+                .with_breaker(service, |b| b.is_available())
+                .unwrap_or(true)
+    }
+
+    pub fn is_up(&self, service: &Service) -> bool {
+        if let Ok(down) = self.down.read() {
+            !down.get(&service.host()).copied().unwrap_or(false)
+        } else {
+            error!("ServiceHealth: Failed to get lock");
+            true
+        }
+    }
+
+    pub fn set_up(&self, service: &Service, up: bool) {
+        if let Ok(mut down) = self.down.write() {
+            down.insert(service.host(), !up);
+        } else {
+            error!("ServiceHealth: Failed to get lock");
+        }
+    }
+
+    // Must be called by the balancers with the service they are about to pick, so
+    // that only one request probes a half-open circuit. Services for which it
+    // returns false cannot be picked.
+    pub fn try_acquire(&self, service: &Service) -> bool {
+        self.is_up(service)
+            && self
+                .with_breaker(service, |b| b.try_acquire())
+                .unwrap_or(true)
+    }
+
+    pub fn record_success(&self, service: &Service) {
+        if let Some(true) = self.with_breaker(service, |b| b.record_success()) {
+            info!("Circuit of service {} is closed", service.host());
+        }
+    }
+
+    pub fn record_failure(&self, service: &Service) {
+        if let Some(true) = self.with_breaker(service, |b| b.record_failure()) {
+            warn!("Circuit of service {} is open", service.host());
+        }
+    }
+
+    fn with_breaker<T, F>(&self, service: &Service, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Breaker) -> T,
+    {
+        let opts = self.breaker_opts.as_ref()?;
+
+        if let Ok(mut breakers) = self.breakers.lock() {
+            let breaker = breakers.entry(service.host()).or_insert_with(|| {
+                Breaker::new(
+                    opts.failure_threshold,
+                    Duration::from_secs(opts.cooldown_secs),
+                )
+            });
+            Some(f(breaker))
+        } else {
+            error!("ServiceHealth: Failed to get lock");
+            None
+        }
+    }
+}
+
+struct ProbeState {
+    service: Service,
+    successes: u32,
+    failures: u32,
+}
+
+#[allow(dead_code)]
+pub struct HealthChecker {
+    task: JoinHandle<()>,
+}
+
+impl HealthChecker {
+    pub fn run(services: Vec<Service>, health: ServiceHealth, opts: HealthCheck) -> Self {
+        let interval = Duration::from_secs(opts.interval_secs);
+        let mut states: Vec<ProbeState> = services
+            .into_iter()
+            .map(|service| ProbeState {
+                service,
+                successes: 0,
+                failures: 0,
+            })
+            .collect();
+
+        let task = tokio::spawn(async move {
+            loop {
+                for state in states.iter_mut() {
+                    Self::check(state, &health, &opts).await;
+                }
+
+                time::sleep(interval).await;
+            }
+        });
+
+        HealthChecker { task }
+    }
+
+    async fn check(state: &mut ProbeState, health: &ServiceHealth, opts: &HealthCheck) {
+        let is_up = health.is_up(&state.service);
+        let timeout = Duration::from_millis(opts.timeout_ms);
+        let result = time::timeout(timeout, Self::probe(&state.service, opts))
+            .await
+            .unwrap_or_else(|_| Err(Error::msg("Health check timed out")));
+
+        match result {
+            Ok(()) => {
+                state.successes += 1;
+                state.failures = 0;
+                if !is_up && state.successes >= opts.rise {
+                    info!("Service {} is back up", state.service.host());
+                    health.set_up(&state.service, true);
+                }
+            }
+            Err(err) => {
+                state.failures += 1;
+                state.successes = 0;
+                if is_up && state.failures >= opts.fall {
+                    warn!("Service {} is down: {:#}", state.service.host(), err);
+                    health.set_up(&state.service, false);
+                }
+            }
+        }
+    }
+
+    async fn probe(service: &Service, opts: &HealthCheck) -> Result<()> {
+        let addr = tokio::net::lookup_host(service.host())
+            .await?
+            .next()
+            .context(format!("Failed to resolve {}", service.host()))?;
+
+        let mut stream = TcpStream::connect(addr).await?;
+
+        let req = format!(
+            "GET {} HTTP/1.1\r\nhost:{}\r\nconnection:close\r\nuser-agent:rusty-proxy\r\n\r\n",
+            opts.path,
+            service.host()
+        );
+        stream.write_all(req.as_bytes()).await?;
+
+        let mut reader = BufReader::new(stream);
+        let res = Response::read(&mut reader, &Method::Get).await?;
+        let status = res.header.status.code.as_u16();
+
+        if status == opts.expected_status {
+            Ok(())
+        } else {
+            Err(Error::msg(format!("Health check replied {}", status)))
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use log::error;
+use std::sync::Mutex;
+
+use crate::balancer::health::ServiceHealth;
+use crate::balancer::{Balancer, RequestContext};
+use crate::opts::Service;
+
+struct Peer {
+    service: Service,
+    outstanding: u64,
+}
+
+// Sends each request to the service with the fewest requests in flight relative
+// to its weight. Ties are broken in round robin order so idle services share the
+// load evenly.
+pub struct LeastConnections {
+    state: Mutex<(Vec<Peer>, usize)>,
+    health: ServiceHealth,
+}
+
+impl LeastConnections {
+    pub fn new(services: Vec<Service>, health: ServiceHealth) -> Self {
+        let peers = services
+            .into_iter()
+            .map(|service| Peer {
+                service,
+                outstanding: 0,
+            })
+            .collect();
+
+        LeastConnections {
+            state: Mutex::new((peers, 0)),
+            health,
+        }
+    }
+}
+
+impl Balancer for LeastConnections {
+    fn pick(&self, req: &RequestContext) -> Option<Service> {
+        if let Ok(mut state) = self.state.lock() {
+            let (peers, next) = &mut *state;
+            let len = peers.len();
+            let mut candidates: Vec<usize> = (0..len)
+                .map(|k| (*next + k) % len)
+                .filter(|i| {
+                    self.health.is_available(&peers[*i].service)
+                        && !req.is_excluded(&peers[*i].service)
+                })
+                .collect();
+
+            // A candidate whose half-open circuit was taken by another request in
+            // the meantime is skipped, and the pick is made among the others.
+            loop {
+                let best = candidates.iter().copied().reduce(|b, i| {
+                    let (p, q) = (&peers[i], &peers[b]);
+                    if p.outstanding * u64::from(q.service.weight())
+                        < q.outstanding * u64::from(p.service.weight())
+                    {
+                        i
+                    } else {
+                        b
+                    }
+                })?;
+
+                if self.health.try_acquire(&peers[best].service) {
+                    *next = (best + 1) % len;
+                    peers[best].outstanding += 1;
+
+                    return Some(peers[best].service.clone());
+                }
+                candidates.retain(|i| *i != best);
+            }
+        } else {
+            error!("LeastConnections: Failed to get lock");
+            None
+        }
+    }
+
+    fn release(&self, service: &Service) {
+        if let Ok(mut state) = self.state.lock() {
+            let (peers, _) = &mut *state;
+            if let Some(peer) = peers
+                .iter_mut()
+                .find(|p| p.service.host() == service.host())
+            {
+                peer.outstanding = peer.outstanding.saturating_sub(1);
+            }
+        } else {
+            error!("LeastConnections: Failed to get lock");
+        }
+    }
+}
@@ -0,0 +1,71 @@
+pub mod circuit;
+pub mod consistent_hash;
+pub mod health;
+pub mod least_conn;
+pub mod weighted_rr;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::http::request::RequestHeader;
+use crate::opts::{HashKey, Service, Strategy};
+use consistent_hash::ConsistentHash;
+use health::ServiceHealth;
+use least_conn::LeastConnections;
+use weighted_rr::WeightedRoundRobin;
+
+pub struct RequestContext<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub header: &'a RequestHeader,
+    // Services that already failed this request.
+    pub excluded: &'a [Service],
+}
+
+impl RequestContext<'_> {
+    pub fn is_excluded(&self, service: &Service) -> bool {
+        self.excluded.iter().any(|s| s.host() == service.host())
+    }
+}
+
+pub trait Balancer: Send + Sync {
+    // Chooses the service that should receive the next request.
+    fn pick(&self, req: &RequestContext) -> Option<Service>;
+
+    // Called once the request sent to a picked service has completed.
+    fn release(&self, _service: &Service) {}
+}
+
+// Releases a picked service once the request sent to it has completed, that is,
+// when the guard is dropped. Streamed responses hold it until their body has been
+// sent to the client.
+pub struct PickGuard {
+    balancer: Arc<dyn Balancer>,
+    service: Service,
+}
+
+impl PickGuard {
+    pub fn new(balancer: Arc<dyn Balancer>, service: Service) -> Self {
+        PickGuard { balancer, service }
+    }
+}
+
+impl Drop for PickGuard {
+    fn drop(&mut self) {
+        self.balancer.release(&self.service);
+    }
+}
+
+pub fn mk_balancer(
+    strategy: &Strategy,
+    hash_key: &HashKey,
+    services: Vec<Service>,
+    health: ServiceHealth,
+) -> Arc<dyn Balancer> {
+    match strategy {
+        Strategy::RoundRobin => Arc::new(WeightedRoundRobin::new(services, health)),
+        Strategy::LeastConnections => Arc::new(LeastConnections::new(services, health)),
+        Strategy::ConsistentHash => {
+            Arc::new(ConsistentHash::new(services, hash_key.clone(), health))
+        }
+    }
+}
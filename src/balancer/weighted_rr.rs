@@ -0,0 +1,133 @@
+use log::error;
+use std::sync::Mutex;
+
+use crate::balancer::health::ServiceHealth;
+use crate::balancer::{Balancer, RequestContext};
+use crate::opts::Service;
+
+struct Peer {
+    service: Service,
+    weight: i64,
+    current_weight: i64,
+}
+
+// Smooth weighted round robin as implemented by nginx: on every pick each peer's
+// current weight grows by its weight, the peer with the highest current weight is
+// chosen and the total weight is subtracted from it. Picks of heavier peers are
+// interleaved with the lighter ones instead of being sent in bursts.
+pub struct WeightedRoundRobin {
+    peers: Mutex<Vec<Peer>>,
+    health: ServiceHealth,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(services: Vec<Service>, health: ServiceHealth) -> Self {
+        let peers = services
+            .into_iter()
+            .map(|service| Peer {
+                weight: service.weight() as i64,
+                current_weight: 0,
+                service,
+            })
+            .collect();
+
+        WeightedRoundRobin {
+            peers: Mutex::new(peers),
+            health,
+        }
+    }
+}
+
+impl Balancer for WeightedRoundRobin {
+    fn pick(&self, req: &RequestContext) -> Option<Service> {
+        if let Ok(mut peers) = self.peers.lock() {
+            // Services that are down are left out of the rotation, as if they were not configured.
+            let mut candidates: Vec<usize> = (0..peers.len())
+                .filter(|i| {
+                    self.health.is_available(&peers[*i].service)
+                        && !req.is_excluded(&peers[*i].service)
+                })
+                .collect();
+
+            // A candidate whose half-open circuit was taken by another request in
+            // the meantime is skipped, and the pick is made among the others.
+            loop {
+                let best = candidates.iter().copied().reduce(|b, i| {
+                    let (p, q) = (&peers[i], &peers[b]);
+                    if p.current_weight + p.weight > q.current_weight + q.weight {
+                        i
+                    } else {
+                        b
+                    }
+                })?;
+
+                if self.health.try_acquire(&peers[best].service) {
+                    let mut total: i64 = 0;
+                    for i in candidates {
+                        peers[i].current_weight += peers[i].weight;
+                        total += peers[i].weight;
+                    }
+                    peers[best].current_weight -= total;
+
+                    return Some(peers[best].service.clone());
+                }
+                candidates.retain(|i| *i != best);
+            }
+        } else {
+            error!("WeightedRoundRobin: Failed to get lock");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::Headers;
+    use crate::http::request::{Method, RequestHeader, RequestLine};
+
+    fn service(port: u16, weight: u32) -> Service {
+        Service {
+            addr: "127.0.0.1".to_string(),
+            port,
+            weight: Some(weight),
+        }
+    }
+
+    fn pick_ports(balancer: &WeightedRoundRobin, n: usize, excluded: &[Service]) -> Vec<u16> {
+        let header = RequestHeader {
+            metadata: RequestLine {
+                method: Method::Get,
+                uri: "/".to_string(),
+                version: "HTTP/1.1".to_string(),
+            },
+            headers: Headers::new(),
+        };
+        let req = RequestContext {
+            client_ip: None,
+            header: &header,
+            excluded,
+        };
+
+        (0..n).map(|_| balancer.pick(&req).unwrap().port).collect()
+    }
+
+    #[test]
+    fn heavier_services_are_interleaved() {
+        let services = vec![service(1, 5), service(2, 1), service(3, 1)];
+        let balancer = WeightedRoundRobin::new(services, ServiceHealth::new(None));
+
+        assert_eq!(
+            pick_ports(&balancer, 14, &[]),
+            vec![1, 1, 2, 1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1]
+        );
+    }
+
+    #[test]
+    fn excluded_services_are_not_picked() {
+        let services = vec![service(1, 2), service(2, 1)];
+        let balancer = WeightedRoundRobin::new(services, ServiceHealth::new(None));
+
+        assert_eq!(pick_ports(&balancer, 3, &[service(1, 2)]), vec![2, 2, 2]);
+    }
+}
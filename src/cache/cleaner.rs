@@ -1,9 +1,14 @@
-use log::info;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::io;
 use std::path::PathBuf;
 use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs, time};
 
-use super::io::{delete_cache_file, CacheFile};
+use super::index::{write_index, INDEX_FILE};
+use super::io::{delete_cache_file, is_temp_file, CacheFile};
+use super::memory::MemoryCache;
 
 #[allow(dead_code)]
 pub struct CacheCleaner {
@@ -12,13 +17,42 @@ pub struct CacheCleaner {
 
 static SLEEP_TIME: u64 = 15; // secs
 
+// A cache file that is kept after a cleaning pass.
+struct Entry {
+    path: PathBuf,
+    key: String,
+    size: u64,
+    last_access: SystemTime,
+    is_evictable: bool,
+}
+
 impl CacheCleaner {
-    pub fn run(cache_dir: PathBuf) -> Self {
+    pub fn run(cache_dir: PathBuf, max_size_bytes: Option<u64>, memory_cache: MemoryCache) -> Self {
         let one_min = time::Duration::from_secs(SLEEP_TIME);
         let thread = thread::spawn(move || loop {
             info!("Cleaning cache ...");
+            info!(
+                "Memory cache: {} hits, {} misses",
+                memory_cache.hits(),
+                memory_cache.misses()
+            );
 
-            Self::traverse_files(cache_dir.clone());
+            Self::record_accesses(memory_cache.take_accesses());
+            let mut entries = Vec::new();
+            Self::traverse_files(cache_dir.clone(), &mut entries, &memory_cache);
+            if let Some(max_size_bytes) = max_size_bytes {
+                Self::evict(&mut entries, max_size_bytes, &memory_cache);
+            }
+            let index = entries
+                .iter()
+                .map(|e| {
+                    let name = e.path.file_name().unwrap_or_default().to_string_lossy();
+                    format!("{} {}", name, e.key)
+                })
+                .collect();
+            if let Err(err) = write_index(&cache_dir, index) {
+                error!("{err:#}");
+            }
 
             thread::sleep(one_min);
         });
@@ -26,23 +60,126 @@ impl CacheCleaner {
         CacheCleaner { thread }
     }
 
-    fn traverse_files(path: PathBuf) {
-        for entry in fs::read_dir(path.as_path()) {
-            for dir_entry in entry {
-                if let Ok(dir_entry) = dir_entry {
-                    let dir_entry_path = dir_entry.path();
-                    if dir_entry_path.is_file() {
-                        if let Ok(metadata) = CacheFile::read_header(&dir_entry_path) {
-                            if metadata.is_expired() {
-                                delete_cache_file(dir_entry_path)
-                                    .expect("Failed to delete expired cache file");
+    // The modification time of a file is its last access, which is not updated
+    // when it is served from memory.
+    fn record_accesses(accesses: HashMap<PathBuf, SystemTime>) {
+        for (path, time) in accesses {
+            let result = fs::File::open(&path).and_then(|file| file.set_modified(time));
+            if let Err(err) = result {
+                if err.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to update access time of {path:?}: {err}");
+                }
+            }
+        }
+    }
+
+    // Deletes the removable files and collects the remaining ones.
+    fn traverse_files(path: PathBuf, entries: &mut Vec<Entry>, memory_cache: &MemoryCache) {
+        if let Ok(entry) = fs::read_dir(path.as_path()) {
+            for dir_entry in entry.flatten() {
+                let dir_entry_path = dir_entry.path();
+                if dir_entry_path.is_file() {
+                    if dir_entry.file_name() == INDEX_FILE || is_temp_file(&dir_entry_path) {
+                        continue;
+                    }
+                    if let Ok(metadata) = CacheFile::read_header(&dir_entry_path) {
+                        if metadata.is_removable() {
+                            memory_cache.remove(&dir_entry_path);
+                            if let Err(err) = delete_cache_file(dir_entry_path) {
+                                error!("{err:#}");
                             }
+                        } else if let Ok(file_metadata) = dir_entry.metadata() {
+                            // The file of a URI whose variants are stored apart is tiny, and
+                            // the variants are unreachable without it.
+                            let is_stub = metadata.vary.is_some()
+                                && !dir_entry.file_name().to_string_lossy().contains('~');
+
+                            entries.push(Entry {
+                                path: dir_entry_path,
+                                key: metadata.key.unwrap_or_default(),
+                                size: file_metadata.len(),
+                                last_access: file_metadata.modified().unwrap_or(UNIX_EPOCH),
+                                is_evictable: !is_stub,
+                            });
                         }
-                    } else {
-                        Self::traverse_files(dir_entry_path);
                     }
+                } else {
+                    Self::traverse_files(dir_entry_path, entries, memory_cache);
                 }
             }
         }
     }
+
+    // Deletes the least recently used files until the cache fits in `max_size_bytes`.
+    fn evict(entries: &mut Vec<Entry>, max_size_bytes: u64, memory_cache: &MemoryCache) {
+        let mut size: u64 = entries.iter().map(|e| e.size).sum();
+        if size <= max_size_bytes {
+            return;
+        }
+
+        entries.sort_by_key(|e| e.last_access);
+        entries.retain(|e| {
+            if size <= max_size_bytes || !e.is_evictable {
+                return true;
+            }
+
+            memory_cache.remove(&e.path);
+            match delete_cache_file(e.path.clone()) {
+                Ok(()) => {
+                    info!("Evicted {} from cache", e.key);
+                    size -= e.size;
+                    false
+                }
+                Err(err) => {
+                    error!("{err:#}");
+                    true
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::io::FileMetadata;
+    use crate::http::cache_control::Freshness;
+    use crate::http::headers::Headers;
+    use std::time::Duration;
+
+    fn cached_entry(memory_cache: &MemoryCache, name: &str, age_secs: u64) -> Entry {
+        let freshness = Freshness {
+            ttl: 60,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+        };
+        let metadata =
+            FileMetadata::new(freshness, (200, "OK".to_string()), 4, Headers::new(), None).unwrap();
+        let path = std::env::temp_dir().join(format!("rusty-proxy-missing-{name}"));
+        memory_cache.insert(CacheFile::new(metadata, path.clone(), b"data".to_vec()).unwrap());
+
+        Entry {
+            path,
+            key: name.to_string(),
+            size: 4,
+            last_access: SystemTime::now() - Duration::from_secs(age_secs),
+            is_evictable: true,
+        }
+    }
+
+    #[test]
+    fn evicted_files_are_removed_from_memory() {
+        let memory_cache = MemoryCache::new(1024);
+        let mut entries = vec![
+            cached_entry(&memory_cache, "new", 0),
+            cached_entry(&memory_cache, "old", 60),
+        ];
+        let (new, old) = (entries[0].path.clone(), entries[1].path.clone());
+
+        CacheCleaner::evict(&mut entries, 4, &memory_cache);
+
+        assert_eq!(entries.len(), 1);
+        assert!(memory_cache.contains(&new));
+        assert!(!memory_cache.contains(&old));
+    }
 }
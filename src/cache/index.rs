@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+// The index maps the name of each cache file back to the resource it stores, one
+// `<name> <key>` entry per line. It is only meant for inspecting the cache.
+pub static INDEX_FILE: &str = "index";
+
+pub fn write_index(cache_dir: &Path, mut entries: Vec<String>) -> Result<()> {
+    entries.sort();
+
+    // The cache dir is only created along with the first cache file.
+    fs::create_dir_all(cache_dir).context(format!("Failed to create cache dir {cache_dir:?}"))?;
+
+    let path = cache_dir.join(INDEX_FILE);
+    let path_tmp = path.with_extension(Uuid::new_v4().to_string());
+
+    let mut data = entries.join("\n");
+    if !data.is_empty() {
+        data.push('\n');
+    }
+    fs::write(&path_tmp, data).context(format!("Failed to write index {path_tmp:?}"))?;
+    fs::rename(&path_tmp, &path).context(format!("Failed to write index {path:?}"))
+}
+
+// Drops the entries of the given files from the index.
+pub fn remove_from_index(cache_dir: &Path, names: &HashSet<String>) -> Result<()> {
+    let path = cache_dir.join(INDEX_FILE);
+    if names.is_empty() || !path.is_file() {
+        return Ok(());
+    }
+
+    let data = fs::read_to_string(&path).context(format!("Failed to read index {path:?}"))?;
+    let entries = data
+        .lines()
+        .filter(|line| {
+            let name = line.split_once(' ').map(|(name, _)| name).unwrap_or(line);
+            !names.contains(name)
+        })
+        .map(str::to_string)
+        .collect();
+
+    write_index(cache_dir, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_is_written_to_a_missing_cache_dir() {
+        let cache_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let entries = vec!["b /b".to_string(), "a /a".to_string()];
+        write_index(&cache_dir, entries).unwrap();
+
+        let names = HashSet::from(["b".to_string()]);
+        remove_from_index(&cache_dir, &names).unwrap();
+        let data = fs::read_to_string(cache_dir.join(INDEX_FILE)).unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(data, "a /a\n");
+    }
+}
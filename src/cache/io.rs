@@ -1,79 +1,232 @@
 use anyhow::{Context, Error, Result};
+use log::warn;
+use sha2::{Digest, Sha256};
 use std::fs::{self, File};
-use std::io::SeekFrom;
-use std::io::{prelude::*, BufReader};
-use std::path::PathBuf;
+use std::io::{self, prelude::*, BufReader};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
+use crate::http::cache_control::{self, Freshness};
+use crate::http::headers::{self, Headers};
+
+// Expired entries with validators are kept on disk for this long so that they
+// can be revalidated with the service instead of downloaded again.
+static REVALIDATE_WINDOW_SECS: u64 = 3600;
+
+// A cache file starts with a prelude made of a magic number, the version of the
+// format and the length of the header that follows it. The header holds the
+// numbers below, the checksum of the body, the key, the reason phrase of the
+// status and the response headers.
+static MAGIC: &[u8; 4] = b"RPCF";
+static FORMAT_VERSION: u16 = 2;
+const PRELUDE_SIZE: usize = 4 + 2 + 4;
+static MAX_HEADER_SIZE: usize = 1024 * 1024;
+// Timestamp, ttl, stale-while-revalidate, stale-if-error, status and content length.
+const HEADER_NUMBERS_SIZE: usize = std::mem::size_of::<u64>() * 6;
+const CHECKSUM_SIZE: usize = 32;
+
+type Checksum = [u8; CHECKSUM_SIZE];
+
+#[derive(Clone)]
 pub struct FileMetadata {
     timestamp: Duration,
     ttl_secs: Duration,
+    stale_while_revalidate_secs: Duration,
+    stale_if_error_secs: Duration,
+    pub status: u16,
+    pub reason: String,
     pub content_type: Option<String>,
     pub content_length: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub vary: Option<String>,
+    pub key: Option<String>,
+    pub headers: Headers,
 }
 
 impl FileMetadata {
     pub fn new(
-        ttl: u64,
+        freshness: Freshness,
+        (status, reason): (u16, String),
         content_length: u64,
-        content_type: Option<String>,
+        headers: Headers,
+        key: Option<String>,
     ) -> Result<FileMetadata> {
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .context(format!("Failed to get system time"))?;
-        Ok(FileMetadata {
-            timestamp,
-            ttl_secs: Duration::from_secs(ttl),
+            .context("Failed to get system time")?;
+        Ok(FileMetadata::with_headers(
+            [
+                timestamp,
+                Duration::from_secs(freshness.ttl),
+                Duration::from_secs(freshness.stale_while_revalidate),
+                Duration::from_secs(freshness.stale_if_error),
+            ],
+            (status, reason),
             content_length,
-            content_type,
-        })
+            headers,
+            key,
+        ))
     }
 
-    fn from_buffer(buffer: &[u8], content_type: String) -> FileMetadata {
-        assert!(buffer.len() == std::mem::size_of::<u64>() * 3);
-
-        let (first, rest) = buffer.split_at(std::mem::size_of::<u64>());
-        let (second, third) = rest.split_at(std::mem::size_of::<u64>());
-        let timestamp = Duration::from_secs(u64::from_le_bytes(first.try_into().unwrap()));
-        let ttl_secs = Duration::from_secs(u64::from_le_bytes(second.try_into().unwrap()));
-        let content_length = u64::from_le_bytes(third.try_into().unwrap());
-        let content_type = if content_type == "" {
-            None
-        } else {
-            Some(content_type.trim().to_string())
-        };
-
+    // The validators, content type and vary of the entry come from its headers.
+    fn with_headers(
+        [timestamp, ttl_secs, stale_while_revalidate_secs, stale_if_error_secs]: [Duration; 4],
+        (status, reason): (u16, String),
+        content_length: u64,
+        headers: Headers,
+        key: Option<String>,
+    ) -> FileMetadata {
         FileMetadata {
-            content_type,
             timestamp,
             ttl_secs,
+            stale_while_revalidate_secs,
+            stale_if_error_secs,
+            status,
+            reason,
+            content_type: headers.get("content-type").cloned(),
             content_length,
+            etag: headers.get("etag").cloned(),
+            last_modified: headers.get("last-modified").cloned(),
+            vary: headers.get("vary").cloned(),
+            key,
+            headers,
+        }
+    }
+
+    fn from_buffer(buffer: &[u8]) -> Result<(FileMetadata, Checksum)> {
+        if buffer.len() < HEADER_NUMBERS_SIZE + CHECKSUM_SIZE {
+            return Err(Error::msg("Truncated cache file header"));
         }
+
+        let (numbers, rest) = buffer.split_at(HEADER_NUMBERS_SIZE);
+        let (checksum, text) = rest.split_at(CHECKSUM_SIZE);
+        let mut numbers = numbers
+            .chunks_exact(std::mem::size_of::<u64>())
+            .map(|n| u64::from_le_bytes(n.try_into().unwrap()));
+        let mut next_number = || numbers.next().unwrap();
+        let durations = [(); 4].map(|_| Duration::from_secs(next_number()));
+        let status = u16::try_from(next_number()).context("Invalid cache file status")?;
+        let content_length = next_number();
+
+        // The key and the reason phrase, followed by one response header per line.
+        let text = std::str::from_utf8(text).context("Invalid cache file header")?;
+        let mut lines = text.lines();
+        let key = lines
+            .next()
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string);
+        let reason = lines.next().unwrap_or_default().trim().to_string();
+        let mut headers = Headers::new();
+        for line in lines {
+            let (k, v) = headers::parse_header(line)?;
+            headers.insert(k, v.to_string());
+        }
+
+        Ok((
+            FileMetadata::with_headers(durations, (status, reason), content_length, headers, key),
+            checksum.try_into().unwrap(),
+        ))
+    }
+
+    fn to_buffer(&self, checksum: &Checksum) -> Vec<u8> {
+        let numbers = [
+            self.timestamp.as_secs(),
+            self.ttl_secs.as_secs(),
+            self.stale_while_revalidate_secs.as_secs(),
+            self.stale_if_error_secs.as_secs(),
+            self.status as u64,
+            self.content_length,
+        ];
+
+        let mut buffer: Vec<u8> = numbers.iter().flat_map(|n| n.to_le_bytes()).collect();
+        buffer.extend_from_slice(checksum);
+        buffer.extend_from_slice(self.key.as_deref().unwrap_or_default().trim().as_bytes());
+        buffer.push(b'\n');
+        buffer.extend_from_slice(self.reason.trim().as_bytes());
+        buffer.push(b'\n');
+        for (k, v) in self.headers.iter() {
+            buffer.extend_from_slice(format!("{k}:{}\n", v.trim()).as_bytes());
+        }
+        buffer
     }
 }
 
 impl FileMetadata {
     pub fn is_expired(&self) -> bool {
+        self.is_expired_for(Duration::ZERO)
+    }
+
+    // Whether the expired entry can be served while it is refreshed in the background.
+    pub fn can_serve_while_revalidating(&self) -> bool {
+        !self.is_expired_for(self.stale_while_revalidate_secs)
+    }
+
+    // Whether the expired entry can be served when the services fail.
+    pub fn can_serve_on_error(&self) -> bool {
+        !self.is_expired_for(self.stale_if_error_secs)
+    }
+
+    fn is_expired_for(&self, extra: Duration) -> bool {
         if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
-            return now >= self.timestamp + self.ttl_secs;
+            now >= self.timestamp + self.ttl_secs + extra
         } else {
-            return false;
+            false
         }
     }
 
-    fn size_bytes(&self) -> u64 {
-        let a = if let Some(ct) = &self.content_type {
-            ct.as_bytes().len() + 1
+    pub fn has_validators(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+
+    // Whether the cleaner can delete the entry, that is, it expired and it can no
+    // longer be revalidated or served stale.
+    pub fn is_removable(&self) -> bool {
+        let window = if self.has_validators() {
+            Duration::from_secs(REVALIDATE_WINDOW_SECS)
         } else {
-            1
+            Duration::ZERO
         };
-        let b = std::mem::size_of::<u64>() * 3;
 
-        return (a + b) as u64;
+        self.is_expired_for(
+            window
+                .max(self.stale_while_revalidate_secs)
+                .max(self.stale_if_error_secs),
+        )
+    }
+
+    // How long ago the service generated the response, counting the time it has
+    // been stored.
+    pub fn age(&self) -> u64 {
+        let resident_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.saturating_sub(self.timestamp).as_secs())
+            .unwrap_or(0);
+        cache_control::age(&self.headers, resident_secs)
+    }
+
+    // Makes the entry fresh again after a successful revalidation, updating the
+    // stored headers with the ones of the `304` response.
+    pub fn refresh(&mut self, freshness: Freshness, headers: &Headers) -> Result<()> {
+        self.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("Failed to get system time")?;
+        self.ttl_secs = Duration::from_secs(freshness.ttl);
+        self.stale_while_revalidate_secs = Duration::from_secs(freshness.stale_while_revalidate);
+        self.stale_if_error_secs = Duration::from_secs(freshness.stale_if_error);
+
+        self.headers.remove("age");
+        self.headers.extend(headers::cacheable_headers(headers));
+        self.content_type = self.headers.get("content-type").cloned();
+        self.etag = self.headers.get("etag").cloned();
+        self.last_modified = self.headers.get("last-modified").cloned();
+        Ok(())
     }
 }
 
+#[derive(Clone)]
 pub struct CacheFile {
     pub metadata: FileMetadata,
     pub path: PathBuf,
@@ -81,22 +234,15 @@ pub struct CacheFile {
 }
 
 impl CacheFile {
-    pub fn new(
-        ttl: u64,
-        content_length: u64,
-        path: PathBuf,
-        content_data: Vec<u8>,
-        content_type: Option<String>,
-    ) -> Result<CacheFile> {
+    pub fn new(metadata: FileMetadata, path: PathBuf, content_data: Vec<u8>) -> Result<CacheFile> {
         if path.as_path().is_dir() {
-            return Err(Error::msg("Cache path is directory"));
+            Err(Error::msg("Cache path is directory"))
         } else {
-            let metadata = FileMetadata::new(ttl, content_length, content_type)?;
-            return Ok(CacheFile {
+            Ok(CacheFile {
                 metadata,
                 path,
                 content_data,
-            });
+            })
         }
     }
 
@@ -105,33 +251,38 @@ impl CacheFile {
             File::open(path.as_path()).context(format!("Failed to read file header {path:?}"))?;
 
         let mut reader = BufReader::new(file);
-        let mut content_type = String::new();
-        let mut buffer = [0u8; std::mem::size_of::<u64>() * 3];
-
-        reader
-            .read_line(&mut content_type)
-            .context(format!("Failed to read header from {path:?}"))?;
-        reader
-            .read_exact(&mut buffer)
-            .context(format!("Failed to read header from {path:?}"))?;
-
-        Ok(FileMetadata::from_buffer(&buffer, content_type))
+        match read_header(&mut reader) {
+            Ok((metadata, _)) => Ok(metadata),
+            Err(err) => Err(discard(path, err)),
+        }
     }
 
-    pub fn read(path: PathBuf, metadata: FileMetadata) -> Result<CacheFile> {
+    // The modification time of a cache file records its last access, which is
+    // used to evict the least recently used files.
+    pub fn read(path: PathBuf) -> Result<CacheFile> {
         let file = File::open(path.as_path()).context(format!("Failed to read file {path:?}"))?;
+        if let Err(err) = file.set_modified(SystemTime::now()) {
+            warn!("Failed to update access time of {path:?}: {err}");
+        }
+
         let mut reader = BufReader::new(file);
-        reader.seek(SeekFrom::Start(metadata.size_bytes())).unwrap();
+        let (metadata, checksum) = read_header(&mut reader).map_err(|err| discard(&path, err))?;
 
         let mut content_data: Vec<u8> = Vec::with_capacity(metadata.content_length as usize);
-        if let Ok(_) = reader.read_to_end(&mut content_data) {
+        reader
+            .read_to_end(&mut content_data)
+            .context(format!("Failed to read file {path:?}"))?;
+
+        if content_data.len() as u64 != metadata.content_length {
+            Err(discard(&path, Error::msg("Truncated cache file body")))
+        } else if Sha256::digest(&content_data).as_slice() != checksum {
+            Err(discard(&path, Error::msg("Checksum mismatch")))
+        } else {
             Ok(CacheFile {
                 metadata,
                 path,
                 content_data,
             })
-        } else {
-            Err(Error::msg(format!("Failed to read cache file")))
         }
     }
 
@@ -140,29 +291,23 @@ impl CacheFile {
         let mut path_tmp = self.path.clone();
         path_tmp.set_extension(ext.to_string());
 
-        let parent = path_tmp
-            .parent()
-            .context(format!("Failed to get parent dir"))?;
+        let parent = path_tmp.parent().context("Failed to get parent dir")?;
 
         fs::create_dir_all(parent).context(format!("Failed to create parent dir {parent:?}"))?;
 
         let mut file =
             File::create(&path_tmp).context(format!("Failed to create file {path_tmp:?}"))?;
-        let ctype_buff = if let Some(ct) = &self.metadata.content_type {
-            let mut ct = ct.clone().trim().to_string();
-            ct.push('\n');
-            ct.as_bytes().to_vec()
-        } else {
-            let mut ct = String::new();
-            ct.push('\n');
-            ct.as_bytes().to_vec()
-        };
-        let timestamp_buff = self.metadata.timestamp.as_secs().to_le_bytes().to_vec();
-        let ttl_buff = self.metadata.ttl_secs.as_secs().to_le_bytes().to_vec();
-        let len_buff = self.metadata.content_length.to_le_bytes().to_vec();
 
         // Write header
-        let header_buff = [ctype_buff, timestamp_buff, ttl_buff, len_buff].concat();
+        let checksum: Checksum = Sha256::digest(&self.content_data).into();
+        let header = self.metadata.to_buffer(&checksum);
+        let header_buff = [
+            MAGIC.as_slice(),
+            &FORMAT_VERSION.to_le_bytes(),
+            &(header.len() as u32).to_le_bytes(),
+            &header,
+        ]
+        .concat();
         let mut pos = 0;
         while pos < header_buff.len() {
             if let Ok(bytes_written) = file.write(&header_buff[pos..]) {
@@ -188,24 +333,229 @@ impl CacheFile {
             }
         }
 
-        std::fs::rename(&path_tmp.as_path(), &self.path.as_path()).unwrap();
+        std::fs::rename(path_tmp.as_path(), self.path.as_path()).unwrap();
 
         Ok(())
     }
 }
 
-pub fn mk_file_path(cache_dir: &PathBuf, uri: String) -> PathBuf {
-    let mut path = cache_dir.clone();
+fn read_header<R: Read>(reader: &mut R) -> Result<(FileMetadata, Checksum)> {
+    let mut prelude = [0u8; PRELUDE_SIZE];
+    reader
+        .read_exact(&mut prelude)
+        .context("Truncated cache file prelude")?;
+
+    if &prelude[..4] != MAGIC {
+        return Err(Error::msg("Not a cache file"));
+    }
+    let version = u16::from_le_bytes(prelude[4..6].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(Error::msg(format!(
+            "Unsupported cache file version {version}"
+        )));
+    }
+    let header_len = u32::from_le_bytes(prelude[6..10].try_into().unwrap()) as usize;
+    if header_len > MAX_HEADER_SIZE {
+        return Err(Error::msg("Cache file header too long"));
+    }
+
+    let mut header = vec![0u8; header_len];
+    reader
+        .read_exact(&mut header)
+        .context("Truncated cache file header")?;
+    FileMetadata::from_buffer(&header)
+}
+
+// Files that are corrupted or in an older format are deleted, so that the
+// resource is fetched again from the services.
+fn discard(path: &Path, err: Error) -> Error {
+    warn!("Discarding cache file {path:?}: {err}");
+    if let Err(err) = fs::remove_file(path) {
+        warn!("Failed to delete file at {path:?}: {err}");
+    }
+    err
+}
+
+// Cache files are named after the hash of their key and spread over two levels
+// of directories, so that no URI can escape the cache directory or clash with
+// another one.
+pub fn mk_file_path(cache_dir: &Path, key: &str) -> PathBuf {
+    let hash = hex::encode(Sha256::digest(key));
+    cache_dir.join(&hash[..2]).join(&hash[2..4]).join(hash)
+}
 
-    if uri.starts_with("/") {
-        path.push(uri.strip_prefix("/").unwrap());
-    } else {
-        path.push(uri);
+// Responses that vary on some request headers are stored next to the file of
+// their URI, under a name derived from the values of those headers.
+pub fn mk_variant_path(path: &Path, vary: &str, req_headers: &Headers) -> PathBuf {
+    let mut hasher = Sha256::new();
+    for name in vary.split(',') {
+        let name = name.trim().to_lowercase();
+        let value = req_headers.get(&name).map(|v| v.trim()).unwrap_or_default();
+        hasher.update(format!("{name}:{value}\n"));
     }
 
-    path
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!("~{}", &hex::encode(hasher.finalize())[..16]));
+    path.with_file_name(file_name)
+}
+
+// Files are written under a temporary name with an extension, and renamed once
+// complete. Cache file names have no extension.
+pub fn is_temp_file(path: &Path) -> bool {
+    path.extension().is_some()
 }
 
+// Files may be deleted concurrently by the cleaner, a purge or a discard, so a
+// file that is already gone counts as deleted.
 pub fn delete_cache_file(path: PathBuf) -> Result<()> {
-    fs::remove_file(path.as_path()).context(format!("Failed to delete file at {path:?}"))
+    match fs::remove_file(path.as_path()) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).context(format!("Failed to delete file at {path:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> FileMetadata {
+        let freshness = Freshness {
+            ttl: 60,
+            stale_while_revalidate: 10,
+            stale_if_error: 20,
+        };
+        let mut headers = Headers::new();
+        headers.insert("content-type".to_string(), "text/html".to_string());
+        headers.insert("etag".to_string(), "\"v1\"".to_string());
+        headers.insert("vary".to_string(), "Accept-Language".to_string());
+        let key = Some("GET http://example.com/".to_string());
+
+        FileMetadata::new(freshness, (200, "OK".to_string()), 5, headers, key).unwrap()
+    }
+
+    fn write_file() -> PathBuf {
+        let path = std::env::temp_dir()
+            .join("rusty-proxy-tests")
+            .join(Uuid::new_v4().simple().to_string());
+        let file = CacheFile::new(metadata(), path.clone(), b"hello".to_vec()).unwrap();
+        file.write().unwrap();
+        path
+    }
+
+    fn assert_same(a: &FileMetadata, b: &FileMetadata) {
+        assert_eq!(a.timestamp.as_secs(), b.timestamp.as_secs());
+        assert_eq!(a.ttl_secs, b.ttl_secs);
+        assert_eq!(a.stale_while_revalidate_secs, b.stale_while_revalidate_secs);
+        assert_eq!(a.stale_if_error_secs, b.stale_if_error_secs);
+        assert_eq!((a.status, &a.reason), (b.status, &b.reason));
+        assert_eq!(a.content_length, b.content_length);
+        assert_eq!(a.content_type, b.content_type);
+        assert_eq!(a.etag, b.etag);
+        assert_eq!(a.vary, b.vary);
+        assert_eq!(a.key, b.key);
+        assert_eq!(a.headers, b.headers);
+    }
+
+    // Overwrites part of a cache file and checks that reading it fails and
+    // deletes it.
+    fn assert_discarded(offset: u64, bytes: &[u8]) {
+        let path = write_file();
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(io::SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+
+        assert!(CacheFile::read(path.clone()).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let metadata = metadata();
+        let checksum = [7u8; CHECKSUM_SIZE];
+        let (read, read_checksum) =
+            FileMetadata::from_buffer(&metadata.to_buffer(&checksum)).unwrap();
+
+        assert_same(&read, &metadata);
+        assert_eq!(read_checksum, checksum);
+        assert!(FileMetadata::from_buffer(&[0u8; HEADER_NUMBERS_SIZE]).is_err());
+    }
+
+    #[test]
+    fn cache_files_roundtrip() {
+        let path = write_file();
+        assert_same(&CacheFile::read_header(&path).unwrap(), &metadata());
+
+        let file = CacheFile::read(path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_same(&file.metadata, &metadata());
+        assert_eq!(file.content_data, b"hello");
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        let path = write_file();
+        let mut data = fs::read(&path).unwrap();
+        data[0] = b'X';
+        assert!(read_header(&mut data.as_slice()).is_err());
+        data[0] = MAGIC[0];
+        data[4..6].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+        assert!(read_header(&mut data.as_slice()).is_err());
+
+        fs::write(&path, &data).unwrap();
+        assert!(CacheFile::read_header(&path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn corrupted_files_are_discarded() {
+        // Wrong magic number.
+        assert_discarded(0, b"XXXX");
+        // Header longer than the file.
+        assert_discarded(6, &u32::MAX.to_le_bytes()[..3]);
+
+        // Truncated body.
+        let path = write_file();
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert!(CacheFile::read(path.clone()).is_err());
+        assert!(!path.exists());
+
+        // Body that does not match its checksum.
+        assert_discarded(len - 1, b"!");
+    }
+
+    #[test]
+    fn cache_files_never_leave_the_cache_dir() {
+        let cache_dir = Path::new("/var/cache/proxy");
+        for key in ["GET http://example.com/../../etc/passwd", "GET /..", ""] {
+            let path = mk_file_path(cache_dir, key);
+            assert!(path.starts_with(cache_dir));
+            assert_eq!(
+                path.components().count(),
+                cache_dir.components().count() + 3
+            );
+        }
+    }
+
+    #[test]
+    fn variants_are_stored_next_to_their_uri() {
+        let path = mk_file_path(Path::new("/var/cache/proxy"), "GET http://example.com/");
+        let mut headers = Headers::new();
+        headers.insert("accept-language".to_string(), "../../en".to_string());
+
+        let variant = mk_variant_path(&path, "Accept-Language", &headers);
+        assert_eq!(variant.parent(), path.parent());
+        assert_ne!(variant, path);
+        assert!(!is_temp_file(&variant));
+
+        headers.insert("accept-language".to_string(), "fr".to_string());
+        assert_ne!(mk_variant_path(&path, "Accept-Language", &headers), variant);
+    }
 }
@@ -0,0 +1,170 @@
+use anyhow::Result;
+use log::error;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use super::io::{CacheFile, FileMetadata};
+
+// Recently served cache files kept in memory in front of the disk cache. It is
+// bounded by the size of the files it holds and evicts the least recently used
+// ones first.
+#[derive(Clone)]
+pub struct MemoryCache {
+    lru: Arc<Mutex<Lru>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl MemoryCache {
+    pub fn new(max_size_bytes: u64) -> Self {
+        MemoryCache {
+            lru: Arc::new(Mutex::new(Lru {
+                max_size: max_size_bytes,
+                ..Lru::default()
+            })),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.with_lru(|lru| lru.files.contains_key(path))
+            .unwrap_or(false)
+    }
+
+    // Same as `CacheFile::read_header`, without touching the disk if the file is
+    // in memory.
+    pub fn read_header(&self, path: &PathBuf) -> Result<FileMetadata> {
+        let metadata = self.with_lru(|lru| {
+            let (file, _) = lru.files.get(path.as_path())?;
+            Some(file.metadata.clone())
+        });
+
+        match metadata {
+            Some(Some(metadata)) => Ok(metadata),
+            _ => CacheFile::read_header(path),
+        }
+    }
+
+    // Same as `CacheFile::read`. Files read from disk are kept in memory.
+    pub fn read(&self, path: PathBuf) -> Result<CacheFile> {
+        if let Some(Some(file)) = self.with_lru(|lru| lru.touch(&path).cloned()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(file);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let file = CacheFile::read(path)?;
+        self.insert(file.clone());
+        Ok(file)
+    }
+
+    pub fn insert(&self, file: CacheFile) {
+        self.with_lru(|lru| lru.insert(file));
+    }
+
+    pub fn remove(&self, path: &Path) {
+        self.with_lru(|lru| lru.remove(path));
+    }
+
+    // Removes the files matching `f` and returns their paths.
+    pub fn remove_where<F>(&self, f: F) -> Vec<PathBuf>
+    where
+        F: Fn(&CacheFile) -> bool,
+    {
+        self.with_lru(|lru| {
+            let paths: Vec<PathBuf> = lru
+                .files
+                .values()
+                .filter(|(file, _)| f(file))
+                .map(|(file, _)| file.path.clone())
+                .collect();
+            paths.iter().for_each(|path| lru.remove(path));
+            paths
+        })
+        .unwrap_or_default()
+    }
+
+    // Times of the hits since the last call, which the cleaner records on disk so
+    // the files served from memory are not the first ones evicted from there.
+    pub fn take_accesses(&self) -> HashMap<PathBuf, SystemTime> {
+        self.with_lru(|lru| std::mem::take(&mut lru.accessed))
+            .unwrap_or_default()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn with_lru<T, F>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Lru) -> T,
+    {
+        if let Ok(mut lru) = self.lru.lock() {
+            Some(f(&mut lru))
+        } else {
+            error!("MemoryCache: Failed to get lock");
+            None
+        }
+    }
+}
+
+// Files are ordered by the tick of their last use.
+#[derive(Default)]
+struct Lru {
+    files: HashMap<PathBuf, (CacheFile, u64)>,
+    order: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    size: u64,
+    max_size: u64,
+    accessed: HashMap<PathBuf, SystemTime>,
+}
+
+impl Lru {
+    fn touch(&mut self, path: &Path) -> Option<&CacheFile> {
+        let (file, tick) = self.files.get_mut(path)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, path.to_path_buf());
+        self.accessed.insert(path.to_path_buf(), SystemTime::now());
+        Some(file)
+    }
+
+    fn insert(&mut self, file: CacheFile) {
+        let size = file.content_data.len() as u64;
+        self.remove(&file.path);
+        if size > self.max_size {
+            return;
+        }
+
+        while self.size + size > self.max_size {
+            if let Some((_, path)) = self.order.pop_first() {
+                if let Some((evicted, _)) = self.files.remove(&path) {
+                    self.size -= evicted.content_data.len() as u64;
+                }
+            } else {
+                break;
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, file.path.clone());
+        self.size += size;
+        self.files.insert(file.path.clone(), (file, self.tick));
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((file, tick)) = self.files.remove(path) {
+            self.order.remove(&tick);
+            self.size -= file.content_data.len() as u64;
+        }
+    }
+}
@@ -1,3 +1,6 @@
 pub mod cleaner;
+pub mod index;
 pub mod io;
+pub mod memory;
+pub mod purge;
 pub mod writer;
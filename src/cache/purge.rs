@@ -0,0 +1,154 @@
+use anyhow::Result;
+use log::{error, info};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::index::{remove_from_index, INDEX_FILE};
+use super::io::{delete_cache_file, is_temp_file, mk_file_path, CacheFile, FileMetadata};
+use super::memory::MemoryCache;
+
+// Cache entries to remove, selected by the URL of the resource they store.
+pub enum Purge {
+    Url(String),
+    Prefix(String),
+    // `*` matches any sequence of characters and `?` any single character.
+    Glob(String),
+    All,
+}
+
+impl Purge {
+    fn matches(&self, metadata: &FileMetadata) -> bool {
+        let key = metadata.key.as_deref().unwrap_or_default();
+        let url = key.split_once(' ').map(|(_, url)| url).unwrap_or(key);
+
+        match self {
+            Purge::Url(target) => url == target,
+            Purge::Prefix(prefix) => url.starts_with(prefix.as_str()),
+            Purge::Glob(pattern) => glob_match(pattern, url),
+            Purge::All => true,
+        }
+    }
+}
+
+// Deletes the matching cache files, both from disk and memory, and returns how
+// many were deleted.
+pub fn purge(cache_dir: &Path, target: &Purge, memory_cache: &MemoryCache) -> Result<usize> {
+    let mut paths = HashSet::new();
+
+    if let Purge::Url(url) = target {
+        // Only the directory of the resource has to be searched, since its variants
+        // are stored next to it.
+        let path = mk_file_path(cache_dir, &format!("GET {url}"));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let is_resource = |path: &Path| {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            file_name == name || file_name.starts_with(&format!("{name}~"))
+        };
+
+        if let Some(Ok(dir)) = path.parent().map(fs::read_dir) {
+            paths.extend(
+                dir.flatten()
+                    .map(|entry| entry.path())
+                    .filter(|path| is_resource(path)),
+            );
+        }
+        paths.extend(memory_cache.remove_where(|file| is_resource(&file.path)));
+    } else {
+        let mut files = Vec::new();
+        collect_files(cache_dir, &mut files);
+        paths.extend(files.into_iter().filter(|path| {
+            matches!(target, Purge::All)
+                || CacheFile::read_header(path).is_ok_and(|metadata| target.matches(&metadata))
+        }));
+        paths.extend(memory_cache.remove_where(|file| target.matches(&file.metadata)));
+    }
+
+    let mut names = HashSet::new();
+    for path in paths.iter() {
+        memory_cache.remove(path);
+        if path.is_file() {
+            if let Err(err) = delete_cache_file(path.clone()) {
+                error!("{err:#}");
+                continue;
+            }
+        }
+        names.insert(
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        );
+    }
+
+    info!("Purged {} files from cache", names.len());
+    remove_from_index(cache_dir, &names)?;
+    Ok(names.len())
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) {
+    if let Ok(entry) = fs::read_dir(path) {
+        for dir_entry in entry.flatten() {
+            let dir_entry_path = dir_entry.path();
+            if dir_entry_path.is_file() {
+                if dir_entry.file_name() != INDEX_FILE && !is_temp_file(&dir_entry_path) {
+                    files.push(dir_entry_path);
+                }
+            } else {
+                collect_files(&dir_entry_path, files);
+            }
+        }
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and of the text it was matched against, to
+    // backtrack to when the rest of the pattern does not match.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_whole_keys() {
+        assert!(glob_match(
+            "GET http://example.com/*",
+            "GET http://example.com/a/b.png"
+        ));
+        assert!(glob_match("*.png", "GET http://example.com/a.png"));
+        assert!(glob_match("*/img?.png", "GET http://example.com/img1.png"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("*", ""));
+
+        assert!(!glob_match("*.png", "GET http://example.com/a.png?v=2"));
+        assert!(!glob_match("*/img?.png", "GET http://example.com/img.png"));
+        assert!(!glob_match(
+            "GET http://example.com/",
+            "GET http://example.com/a"
+        ));
+        assert!(!glob_match("*a*b", "xxaxxbxxa"));
+    }
+}
@@ -3,6 +3,7 @@ use std::sync::mpsc::Receiver;
 use std::thread::{self, JoinHandle};
 
 use crate::cache::io::CacheFile;
+use crate::cache::memory::MemoryCache;
 
 #[allow(dead_code)]
 pub struct CacheWriter {
@@ -10,14 +11,22 @@ pub struct CacheWriter {
 }
 
 impl CacheWriter {
-    pub fn run(cache_receiver: Receiver<CacheFile>) -> Self {
+    pub fn run(cache_receiver: Receiver<CacheFile>, memory_cache: MemoryCache) -> Self {
         let thread = thread::spawn(move || loop {
             if let Ok(cache_file) = cache_receiver.recv() {
-                if cache_file.path.as_path().is_file() {
+                // Expired files are replaced, either by a new version of the resource or
+                // by a revalidated copy.
+                let is_fresh = CacheFile::read_header(&cache_file.path)
+                    .map(|metadata| !metadata.is_expired())
+                    .unwrap_or(false);
+
+                if is_fresh {
                     info!("File already exists. Not writing");
                 } else {
                     if let Err(error) = cache_file.write() {
                         error!("{error}");
+                    } else {
+                        memory_cache.insert(cache_file);
                     }
                 }
             } else {
@@ -1,2 +0,0 @@
-pub mod ccfifo_queue;
-pub mod pool;
@@ -1,67 +0,0 @@
-use log::{error, info};
-use std::{
-    sync::{
-        mpsc::{self},
-        Arc, Mutex,
-    },
-    thread,
-};
-
-type Job = Box<dyn FnOnce() + Send + 'static>;
-
-impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(size);
-
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-            info!("Created worker {id}");
-        }
-        ThreadPool { workers, sender }
-    }
-
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
-        if let Err(_) = self.sender.send(job) {
-            error!("Failed to send job");
-        }
-    }
-}
-
-#[allow(dead_code)]
-struct Worker {
-    id: usize,
-    thread: thread::JoinHandle<()>,
-}
-
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            if let Ok(lock) = receiver.lock() {
-                if let Ok(job) = lock.recv() {
-                    drop(lock);
-                    info!("Executing job in Worker-{id}");
-                    job();
-                } else {
-                    error!("Worker-{id}: Failed to receive job");
-                }
-            } else {
-                error!("Worker-{id}: Failed to get lock");
-            }
-        });
-
-        Worker { id, thread }
-    }
-}
-
-#[allow(dead_code)]
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
-}
@@ -0,0 +1,126 @@
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::time;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::cache::memory::MemoryCache;
+use crate::cache::purge::{purge, Purge};
+use crate::http::request::{Method, Request};
+use crate::http::response::{Code, Response};
+
+#[allow(dead_code)]
+pub struct AdminServer {
+    task: JoinHandle<()>,
+}
+
+static READ_TIMEOUT: u64 = 5; // secs
+static MAX_BODY_SIZE: u64 = 64 * 1024;
+
+// Serves the admin API on its own listener, one connection at a time:
+//
+// - `DELETE /cache?url=<url>` purges a single resource, with all its variants.
+// - `DELETE /cache?prefix=<prefix>` purges the resources whose URL starts with `prefix`.
+// - `DELETE /cache?glob=<pattern>` purges the resources whose URL matches `pattern`.
+// - `DELETE /cache` flushes the entire cache.
+impl AdminServer {
+    pub fn run(listener: TcpListener, cache_dir: PathBuf, memory_cache: MemoryCache) -> Self {
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => handle_connection(stream, &cache_dir, &memory_cache).await,
+                    Err(err) => warn!("{:?}", err),
+                }
+            }
+        });
+
+        AdminServer { task }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, cache_dir: &Path, memory_cache: &MemoryCache) {
+    let timeout = time::Duration::from_secs(READ_TIMEOUT);
+    let mut reader = BufReader::new(stream);
+
+    let mut res =
+        match tokio::time::timeout(timeout, Request::read(&mut reader, MAX_BODY_SIZE)).await {
+            Ok(Ok(req)) => {
+                req.header.pretty_log();
+                handle_request(&req, cache_dir, memory_cache).await
+            }
+            _ => Response::response400(),
+        };
+
+    res.header
+        .insert_header("connection".to_string(), "close".to_string());
+    if let Err(err) = res.write(reader.get_mut()).await {
+        warn!("Admin: {err:#}");
+    }
+}
+
+async fn handle_request(req: &Request, cache_dir: &Path, memory_cache: &MemoryCache) -> Response {
+    let Ok(uri) = Url::parse("http://admin").and_then(|base| base.join(&req.header.metadata.uri))
+    else {
+        return Response::response400();
+    };
+
+    if uri.path() != "/cache" {
+        return Response::plain_text(Code::Code404, "Not Found", "Not found\n".to_string());
+    }
+
+    if req.header.metadata.method != Method::Delete {
+        let mut res = Response::plain_text(
+            Code::Code405,
+            "Method Not Allowed",
+            "Only DELETE is allowed\n".to_string(),
+        );
+        res.header
+            .insert_header("allow".to_string(), "DELETE".to_string());
+        return res;
+    }
+
+    let target = match parse_target(&uri) {
+        Ok(target) => target,
+        Err(msg) => return Response::plain_text(Code::Code400, "Bad Request", format!("{msg}\n")),
+    };
+
+    // Purging walks the cache directory, which is left to the blocking pool.
+    let (cache_dir, memory_cache) = (cache_dir.to_path_buf(), memory_cache.clone());
+    let result = tokio::task::spawn_blocking(move || purge(&cache_dir, &target, &memory_cache))
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+
+    match result {
+        Ok(count) => {
+            info!("Admin: Purged {count} files from cache");
+            Response::plain_text(Code::Code200, "OK", format!("Purged {count} files\n"))
+        }
+        Err(err) => {
+            error!("{err:#}");
+            Response::response500()
+        }
+    }
+}
+
+fn parse_target(uri: &Url) -> Result<Purge, String> {
+    let mut pairs = uri.query_pairs();
+    let target = match pairs.next() {
+        None => Purge::All,
+        // URLs are normalized the same way as the keys of the cache.
+        Some((k, v)) if k == "url" => match Url::parse(&v) {
+            Ok(url) => Purge::Url(url.to_string()),
+            Err(_) => return Err(format!("Invalid url: {v}")),
+        },
+        Some((k, v)) if k == "prefix" => Purge::Prefix(v.to_string()),
+        Some((k, v)) if k == "glob" => Purge::Glob(v.to_string()),
+        Some((k, _)) => return Err(format!("Unknown parameter: {k}")),
+    };
+
+    if pairs.next().is_some() {
+        return Err("Only one of url, prefix or glob is allowed".to_string());
+    }
+
+    Ok(target)
+}
@@ -0,0 +1,721 @@
+use anyhow::{Context, Error, Result};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::balancer::PickGuard;
+use crate::http::chunked::{self, ChunkedWriter};
+use crate::http::headers::Headers;
+use crate::http::request::{self, Rejected};
+use crate::http::response::{Code, Response, ResponseBody, ResponseHeader};
+use crate::http::upstream::{UpstreamConn, UpstreamPool};
+use crate::opts::Service;
+
+static BUFFER_SIZE: usize = 16384;
+
+// How the end of a message body is found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+    // Only responses without length or chunked coding end when the connection closes.
+    UntilClose,
+}
+
+impl Framing {
+    pub fn from_headers(headers: &Headers) -> Option<Framing> {
+        if chunked::is_chunked(headers) {
+            Some(Framing::Chunked)
+        } else {
+            headers
+                .get("content-length")
+                .and_then(|len| len.trim().parse().ok())
+                .map(Framing::Length)
+        }
+    }
+
+    // The rest of a client connection can't be read if the end of a request body
+    // is ambiguous, so such requests are rejected (RFC 9112 6.3). Requests
+    // without a length or chunked coding have no body.
+    //
+    // The returned flag is set when a chunked request also has a content-length,
+    // which may have been sent to smuggle a request: the connection must be closed
+    // after replying to it.
+    pub fn from_request_headers(headers: &Headers) -> Result<(Option<Framing>, bool)> {
+        if let Some(te) = headers.get("transfer-encoding") {
+            return if !chunked::is_chunked(headers) {
+                Err(Error::msg(format!("Invalid transfer-encoding: {te:?}")))
+            } else if chunked::has_other_codings(headers) {
+                Err(Error::new(Rejected {
+                    code: Code::Code501,
+                    reason: "Not Implemented",
+                }))
+            } else {
+                Ok((
+                    Some(Framing::Chunked),
+                    headers.contains_key("content-length"),
+                ))
+            };
+        }
+
+        match headers.get("content-length").map(|len| len.trim()) {
+            Some(len) if !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()) => len
+                .parse()
+                .map(|len| (Some(Framing::Length(len)), false))
+                .context(format!("Invalid content-length: {len:?}")),
+            Some(len) => Err(Error::msg(format!("Invalid content-length: {len:?}"))),
+            None => Ok((None, false)),
+        }
+    }
+}
+
+// Reads a message body as it arrives, removing the chunked coding. It stops at
+// the end of the body, so the underlying reader can be used for the next message.
+pub struct BodyReader<R: AsyncBufRead + Unpin> {
+    inner: R,
+    framing: Framing,
+    // Bytes left in the body, or in the current chunk.
+    remaining: u64,
+    done: bool,
+    trailers: Headers,
+}
+
+impl<R: AsyncBufRead + Unpin> BodyReader<R> {
+    pub fn new(inner: R, framing: Framing) -> Self {
+        let remaining = match framing {
+            Framing::Length(len) => len,
+            _ => 0,
+        };
+
+        BodyReader {
+            inner,
+            framing,
+            remaining,
+            done: remaining == 0 && matches!(framing, Framing::Length(_)),
+            trailers: Headers::new(),
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Trailer fields of a chunked body, once it has been read completely.
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Once the body has been read whole, `len` bytes of it, the message can be
+    // sent on with a known length.
+    pub fn set_length(&self, headers: &mut Headers, len: usize) {
+        match self.framing {
+            Framing::Chunked => chunked::dechunk_headers(headers, len, self.trailers.clone()),
+            Framing::UntilClose => {
+                headers.insert("content-length".to_string(), len.to_string());
+            }
+            Framing::Length(_) => {}
+        }
+    }
+
+    // Reads the next bytes of the body into `buf`, returning 0 at its end.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.framing {
+            Framing::Length(_) => {
+                let n = self.read_data(buf).await?;
+                self.done = self.remaining == 0;
+                Ok(n)
+            }
+            Framing::Chunked => {
+                if self.remaining == 0 {
+                    let size = chunked::read_chunk_size(&mut self.inner)
+                        .await
+                        .map_err(io::Error::other)?;
+                    if size == 0 {
+                        self.trailers = chunked::read_trailers(&mut self.inner)
+                            .await
+                            .map_err(io::Error::other)?;
+                        self.done = true;
+                        return Ok(0);
+                    }
+                    self.remaining = size as u64;
+                }
+
+                let n = self.read_data(buf).await?;
+                if self.remaining == 0 {
+                    chunked::read_chunk_end(&mut self.inner)
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Ok(n)
+            }
+            Framing::UntilClose => {
+                let n = self.inner.read(buf).await?;
+                self.done = n == 0;
+                Ok(n)
+            }
+        }
+    }
+
+    // Reads the rest of the body into `data`, stopping once it holds more than
+    // `limit` bytes.
+    pub async fn read_to_end(&mut self, data: &mut Vec<u8>, limit: u64) -> io::Result<()> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        while data.len() as u64 <= limit {
+            let n = self.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    }
+
+    async fn read_data(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..len]).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before end of body",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// Bodies larger than this are kept in a temporary file instead of memory.
+static SPOOL_THRESHOLD: u64 = 1024 * 1024;
+// Parts of a streamed request body read ahead of the service.
+static STREAM_BUFFERS: usize = 4;
+
+// A request body, which has to be kept around in case the request is sent to
+// another service after a failure. Bodies of requests that are never sent twice
+// are streamed instead.
+#[derive(Debug, Clone)]
+pub enum RequestBody {
+    Memory(Vec<u8>),
+    Spooled(Arc<Spool>),
+    Streamed(Arc<StreamedBody>),
+}
+
+impl RequestBody {
+    // Bodies of chunked requests are only known to be too large once more than
+    // `max_size` bytes have been read.
+    pub async fn read<R: AsyncBufRead + Unpin>(
+        reader: &mut BodyReader<R>,
+        max_size: u64,
+    ) -> Result<(RequestBody, u64)> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data, SPOOL_THRESHOLD.min(max_size))
+            .await
+            .context("Error while reading request body")?;
+
+        if data.len() as u64 > max_size {
+            return Err(request::content_too_large());
+        }
+        if reader.is_done() {
+            let len = data.len() as u64;
+            return Ok((RequestBody::Memory(data), len));
+        }
+
+        let spool = Spool::new();
+        let mut file = File::create(&spool.path)
+            .await
+            .context("Failed to create spool file")?;
+        let mut len = 0;
+        loop {
+            file.write_all(&data)
+                .await
+                .context("Failed to write spool file")?;
+            len += data.len() as u64;
+            if len > max_size {
+                return Err(request::content_too_large());
+            }
+
+            data.resize(BUFFER_SIZE, 0);
+            let n = reader
+                .read(&mut data)
+                .await
+                .context("Error while reading request body")?;
+            if n == 0 {
+                break;
+            }
+            data.truncate(n);
+        }
+        file.flush().await.context("Failed to write spool file")?;
+
+        Ok((RequestBody::Spooled(Arc::new(spool)), len))
+    }
+
+    // The body is piped by the returned pump as the request is sent. Chunked
+    // bodies are forwarded with the chunked coding, without their trailers.
+    pub fn stream<R: AsyncBufRead + Unpin>(
+        reader: BodyReader<R>,
+        max_size: u64,
+    ) -> (RequestBody, BodyPump<R>) {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFERS);
+        let body = StreamedBody {
+            chunked: reader.framing() == Framing::Chunked,
+            receiver: Mutex::new(Some(receiver)),
+        };
+        let pump = BodyPump {
+            reader,
+            sender,
+            max_size,
+        };
+
+        (RequestBody::Streamed(Arc::new(body)), pump)
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        matches!(self, RequestBody::Streamed(_))
+    }
+
+    // Stops the pump of a streamed body that was not sent, or not sent whole.
+    pub fn close(&self) {
+        if let RequestBody::Streamed(body) = self {
+            body.take_receiver();
+        }
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            RequestBody::Memory(data) => writer.write_all(data).await?,
+            RequestBody::Spooled(spool) => {
+                let mut file = File::open(&spool.path)
+                    .await
+                    .context("Failed to open spool file")?;
+                tokio::io::copy(&mut file, writer).await?;
+            }
+            RequestBody::Streamed(body) => {
+                let mut receiver = body
+                    .take_receiver()
+                    .context("Streamed request body was already sent")?;
+                if body.chunked {
+                    let mut writer = ChunkedWriter::new(&mut *writer);
+                    while let Some(data) = receiver.recv().await {
+                        writer.write_all(&data.context(BodyAborted)?).await?;
+                    }
+                    writer.finish(&Headers::new()).await?;
+                } else {
+                    while let Some(data) = receiver.recv().await {
+                        writer.write_all(&data.context(BodyAborted)?).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Error of a streamed body that the client did not send whole.
+#[derive(Debug)]
+pub struct BodyAborted;
+
+impl fmt::Display for BodyAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request body was not received")
+    }
+}
+
+#[derive(Debug)]
+pub struct StreamedBody {
+    chunked: bool,
+    receiver: Mutex<Option<mpsc::Receiver<io::Result<Vec<u8>>>>>,
+}
+
+impl StreamedBody {
+    fn take_receiver(&self) -> Option<mpsc::Receiver<io::Result<Vec<u8>>>> {
+        self.receiver
+            .lock()
+            .ok()
+            .and_then(|mut receiver| receiver.take())
+    }
+}
+
+// Reads a streamed request body from the client and hands it over to the request
+// sent to the service.
+pub struct BodyPump<R: AsyncBufRead + Unpin> {
+    reader: BodyReader<R>,
+    sender: mpsc::Sender<io::Result<Vec<u8>>>,
+    max_size: u64,
+}
+
+impl<R: AsyncBufRead + Unpin> BodyPump<R> {
+    // Fails when the body was not read whole, in which case nothing else can be
+    // read from the client connection.
+    pub async fn run(mut self) -> Result<()> {
+        let mut len = 0;
+        loop {
+            let mut buf = vec![0u8; BUFFER_SIZE];
+            let n = match self.reader.read(&mut buf).await {
+                Ok(n) => n,
+                Err(err) => {
+                    let _ = self
+                        .sender
+                        .send(Err(io::Error::other(err.to_string())))
+                        .await;
+                    return Err(Error::new(err).context("Error while reading request body"));
+                }
+            };
+            if n == 0 {
+                return Ok(());
+            }
+
+            len += n as u64;
+            if len > self.max_size {
+                let err = io::Error::other("Request body too large");
+                let _ = self.sender.send(Err(err)).await;
+                return Err(request::content_too_large());
+            }
+
+            buf.truncate(n);
+            if self.sender.send(Ok(buf)).await.is_err() {
+                return Err(Error::msg("Request was answered before its body was read"));
+            }
+        }
+    }
+}
+
+// A temporary file that is deleted once the body is no longer needed.
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+}
+
+impl Spool {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("rusty_proxy-{}", Uuid::new_v4()));
+        Spool { path }
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Called with the complete response once a teed body has been piped.
+pub type CompletionHook = Box<dyn FnOnce(&Response) + Send + Sync>;
+
+// A response body that is piped from the service to the client as it arrives,
+// starting with the bytes already read from it.
+pub struct BodyStream {
+    prefix: Vec<u8>,
+    reader: BodyReader<UpstreamConn>,
+    // Where the connection goes once the body has been read completely.
+    pool: Option<(UpstreamPool, Service)>,
+    // The service stays busy for the balancer until the body has been piped.
+    guard: Option<PickGuard>,
+    tee: Option<Tee>,
+}
+
+// Copy of a streamed body, kept for the hooks as long as it is no larger than
+// `max_size`. Past that the copy is dropped, and the hooks along with it.
+struct Tee {
+    header: ResponseHeader,
+    data: Vec<u8>,
+    max_size: u64,
+    hooks: Vec<CompletionHook>,
+}
+
+impl BodyStream {
+    pub fn new(
+        prefix: Vec<u8>,
+        reader: BodyReader<UpstreamConn>,
+        pool: Option<(UpstreamPool, Service)>,
+    ) -> Self {
+        BodyStream {
+            prefix,
+            reader,
+            pool,
+            guard: None,
+            tee: None,
+        }
+    }
+
+    pub fn set_guard(&mut self, guard: PickGuard) {
+        self.guard = Some(guard);
+    }
+
+    // Copies the body as it is piped, so that the response can be used whole
+    // afterwards. `header` is the header of that complete response.
+    pub fn tee(&mut self, header: ResponseHeader, max_size: u64) {
+        self.tee = Some(Tee {
+            header,
+            data: Vec::new(),
+            max_size,
+            hooks: Vec::new(),
+        });
+    }
+
+    // Hooks of a body that is not teed are dropped right away.
+    pub fn on_complete(&mut self, hook: CompletionHook) {
+        if let Some(tee) = &mut self.tee {
+            tee.hooks.push(hook);
+        }
+    }
+
+    // Bodies without a known length are sent to the client with the chunked coding.
+    pub fn is_chunked(&self) -> bool {
+        !matches!(self.reader.framing(), Framing::Length(_))
+    }
+
+    pub async fn pipe<W: AsyncWrite + Unpin>(mut self, writer: &mut W) -> io::Result<()> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let prefix = std::mem::take(&mut self.prefix);
+        self.copy(&prefix);
+
+        if self.is_chunked() {
+            let mut writer = ChunkedWriter::new(&mut *writer);
+            writer.write_all(&prefix).await?;
+            loop {
+                let n = self.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                writer.write_all(&buf[..n]).await?;
+            }
+            writer.finish(self.reader.trailers()).await?;
+        } else {
+            writer.write_all(&prefix).await?;
+            loop {
+                let n = self.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                writer.write_all(&buf[..n]).await?;
+            }
+            writer.flush().await?;
+        }
+
+        if let Some(tee) = self.tee.take() {
+            let mut header = tee.header;
+            self.reader.set_length(&mut header.headers, tee.data.len());
+            let res = Response {
+                header,
+                body: ResponseBody::Full(tee.data),
+            };
+            for hook in tee.hooks {
+                hook(&res);
+            }
+        }
+        if let Some((pool, service)) = self.pool {
+            pool.put(&service, self.reader.into_inner());
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf).await?;
+        self.copy(&buf[..n]);
+        Ok(n)
+    }
+
+    fn copy(&mut self, data: &[u8]) {
+        if let Some(tee) = &mut self.tee {
+            if (tee.data.len() + data.len()) as u64 > tee.max_size {
+                self.tee = None;
+            } else {
+                tee.data.extend_from_slice(data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::parse_headers;
+
+    fn framing_and_close(fields: &str) -> Result<(Option<Framing>, bool)> {
+        Framing::from_request_headers(&parse_headers(&format!("{fields}\r\n\r\n"))?)
+    }
+
+    fn framing(fields: &str) -> Result<Option<Framing>> {
+        framing_and_close(fields).map(|(framing, _)| framing)
+    }
+
+    #[test]
+    fn request_framing() {
+        assert_eq!(framing("host: a").unwrap(), None);
+        assert_eq!(
+            framing_and_close("content-length: 12").unwrap(),
+            (Some(Framing::Length(12)), false)
+        );
+        assert_eq!(
+            framing_and_close("transfer-encoding: chunked").unwrap(),
+            (Some(Framing::Chunked), false)
+        );
+        assert_eq!(
+            framing_and_close("transfer-encoding: chunked\r\ncontent-length: 12").unwrap(),
+            (Some(Framing::Chunked), true)
+        );
+    }
+
+    #[test]
+    fn ambiguous_request_framing_is_rejected() {
+        assert!(framing("content-length: abc").is_err());
+        assert!(framing("content-length: +12").is_err());
+        assert!(framing("content-length:").is_err());
+        assert!(framing("content-length: 12\r\ncontent-length: 12").is_err());
+        assert!(framing("content-length: 12\r\nContent-Length: 13").is_err());
+        assert!(framing("transfer-encoding: gzip").is_err());
+        assert!(framing("transfer-encoding: chunked, gzip").is_err());
+        assert!(framing("transfer-encoding: chunked\r\ntransfer-encoding: gzip").is_err());
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    // Streams `input` through a request body, returning what is sent to the
+    // service and the result of the pump.
+    fn stream(
+        input: &'static [u8],
+        framing: Framing,
+        max_size: u64,
+    ) -> (Result<Vec<u8>>, Result<()>) {
+        let (body, pump) = RequestBody::stream(BodyReader::new(input, framing), max_size);
+        block_on(async {
+            let pumped = tokio::spawn(pump.run());
+            let mut out = Vec::new();
+            let written = body.write(&mut out).await.map(|_| out);
+            (written, pumped.await.unwrap())
+        })
+    }
+
+    #[test]
+    fn streamed_bodies_keep_their_framing() {
+        let (out, pumped) = stream(b"hello", Framing::Length(5), 5);
+        assert_eq!(out.unwrap(), b"hello");
+        assert!(pumped.is_ok());
+
+        let (out, pumped) = stream(
+            b"3\r\nhel\r\n2\r\nlo\r\n0\r\nx: y\r\n\r\n",
+            Framing::Chunked,
+            5,
+        );
+        let out = out.unwrap();
+        assert!(pumped.is_ok());
+        assert!(out.ends_with(b"0\r\n\r\n"));
+
+        let mut data = Vec::new();
+        let mut reader = BodyReader::new(out.as_slice(), Framing::Chunked);
+        block_on(reader.read_to_end(&mut data, 5)).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn streamed_bodies_over_the_limit_are_aborted() {
+        let (out, pumped) = stream(b"3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n", Framing::Chunked, 4);
+        assert!(out.unwrap_err().downcast_ref::<BodyAborted>().is_some());
+        let err = pumped.unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejected>().unwrap().code.as_u16(), 413);
+    }
+
+    #[test]
+    fn streamed_bodies_are_sent_once() {
+        let (body, pump) = RequestBody::stream(BodyReader::new(&b""[..], Framing::Length(0)), 0);
+        block_on(pump.run()).unwrap();
+        let mut out = Vec::new();
+        assert!(block_on(body.write(&mut out)).is_ok());
+        assert!(block_on(body.clone().write(&mut out)).is_err());
+    }
+
+    // Pipes a body sent by a service through a teed stream, returning what the
+    // client gets and the response handed to the hooks.
+    fn pipe_teed(
+        input: &'static [u8],
+        framing: Framing,
+        max_size: u64,
+    ) -> (Vec<u8>, Option<Response>) {
+        let header = Response::plain_text(Code::Code200, "OK", String::new()).header;
+        let completed = Arc::new(Mutex::new(None));
+        let slot = completed.clone();
+
+        let out = block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let conn = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (mut service, _) = listener.accept().await.unwrap();
+            service.write_all(input).await.unwrap();
+            drop(service);
+
+            let reader = BodyReader::new(UpstreamConn::new(conn), framing);
+            let mut stream = BodyStream::new(Vec::new(), reader, None);
+            stream.tee(header, max_size);
+            stream.on_complete(Box::new(move |res| {
+                *slot.lock().unwrap() = res.try_clone();
+            }));
+
+            let mut out = Vec::new();
+            stream.pipe(&mut out).await.unwrap();
+            out
+        });
+        let res = completed.lock().unwrap().take();
+        (out, res)
+    }
+
+    #[test]
+    fn teed_bodies_are_completed() {
+        let input = b"3\r\nhel\r\n2\r\nlo\r\n0\r\nx-sum: abc\r\n\r\n";
+        let (out, res) = pipe_teed(input, Framing::Chunked, 5);
+        assert!(out.ends_with(b"x-sum:abc\r\n\r\n"));
+
+        let res = res.unwrap();
+        assert!(matches!(&res.body, ResponseBody::Full(body) if body == b"hello"));
+        assert_eq!(res.header.get_content_length(), Some(5));
+        assert_eq!(
+            res.header.headers.get("x-sum").map(String::as_str),
+            Some("abc")
+        );
+
+        let (out, res) = pipe_teed(b"hello", Framing::UntilClose, 5);
+        assert_eq!(out, b"5\r\nhello\r\n0\r\n\r\n");
+        assert_eq!(res.unwrap().header.get_content_length(), Some(5));
+    }
+
+    #[test]
+    fn teed_bodies_over_the_limit_are_not_completed() {
+        let (out, res) = pipe_teed(b"hello", Framing::Length(5), 4);
+        assert_eq!(out, b"hello");
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn unknown_transfer_codings_are_not_implemented() {
+        let err = framing("transfer-encoding: gzip, chunked").unwrap_err();
+        let rejected = err.downcast_ref::<Rejected>().unwrap();
+        assert_eq!(rejected.code.as_u16(), 501);
+
+        let err = framing("transfer-encoding: gzip").unwrap_err();
+        assert!(err.downcast_ref::<Rejected>().is_none());
+    }
+}
@@ -0,0 +1,242 @@
+use std::time::SystemTime;
+
+use crate::http::headers::Headers;
+
+// Caching directives of a response, see RFC 9111. Unknown directives are
+// ignored.
+#[derive(Debug, Default)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub must_revalidate: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &Headers) -> Self {
+        let mut cc = CacheControl::default();
+
+        if let Some(value) = headers.get("cache-control") {
+            for directive in value.split(',') {
+                let (name, arg) = match directive.split_once('=') {
+                    Some((name, arg)) => (name, Some(arg.trim().trim_matches('"'))),
+                    None => (directive, None),
+                };
+
+                match name.trim().to_lowercase().as_str() {
+                    "no-store" => cc.no_store = true,
+                    "no-cache" => cc.no_cache = true,
+                    "private" => cc.private = true,
+                    "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                    "max-age" => cc.max_age = arg.and_then(|s| s.parse().ok()),
+                    "s-maxage" => cc.s_maxage = arg.and_then(|s| s.parse().ok()),
+                    // Extensions of RFC 5861
+                    "stale-while-revalidate" => {
+                        cc.stale_while_revalidate = arg.and_then(|s| s.parse().ok())
+                    }
+                    "stale-if-error" => cc.stale_if_error = arg.and_then(|s| s.parse().ok()),
+                    _ => {}
+                }
+            }
+        } else if let Some(pragma) = headers.get("pragma") {
+            // `Pragma` is only honored by HTTP/1.0 caches when `Cache-Control` is absent.
+            cc.no_cache = pragma
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case("no-cache"));
+        }
+
+        cc
+    }
+}
+
+// Number of seconds a response stays fresh in a shared cache, and for how long
+// after that it can still be served while it is refreshed in the background or
+// when the services fail.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Freshness {
+    pub ttl: u64,
+    pub stale_while_revalidate: u64,
+    pub stale_if_error: u64,
+}
+
+// Freshness of a response, or `None` if it must not be stored at all. The
+// `defaults` apply when the service gives no explicit values.
+pub fn freshness(headers: &Headers, defaults: &Freshness) -> Option<Freshness> {
+    let cc = CacheControl::parse(headers);
+
+    if cc.no_store || cc.private {
+        return None;
+    }
+
+    let lifetime = if cc.no_cache {
+        0
+    } else if let Some(secs) = cc.s_maxage.or(cc.max_age) {
+        secs
+    } else if let Some(expires) = headers.get("expires") {
+        // An invalid date such as "0" means the response is already expired.
+        let expires = httpdate::parse_http_date(expires).ok();
+        let date = headers
+            .get("date")
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .unwrap_or_else(SystemTime::now);
+
+        expires
+            .and_then(|expires| expires.duration_since(date).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    } else {
+        defaults.ttl
+    };
+
+    // Stale responses can't be served at all if the service asks for them to be
+    // revalidated.
+    let (stale_while_revalidate, stale_if_error) = if cc.no_cache || cc.must_revalidate {
+        (0, 0)
+    } else {
+        (
+            cc.stale_while_revalidate
+                .unwrap_or(defaults.stale_while_revalidate),
+            cc.stale_if_error.unwrap_or(defaults.stale_if_error),
+        )
+    };
+
+    Some(Freshness {
+        ttl: lifetime.saturating_sub(current_age(headers)),
+        stale_while_revalidate,
+        stale_if_error,
+    })
+}
+
+// Time the response already spent in caches further upstream.
+fn current_age(headers: &Headers) -> u64 {
+    age(headers, 0)
+}
+
+// Age of a response that has been stored for `resident_secs`, given its `Age` and
+// `Date` headers.
+pub fn age(headers: &Headers, resident_secs: u64) -> u64 {
+    let age = headers
+        .get("age")
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+        + resident_secs;
+    let apparent_age = headers
+        .get("date")
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .and_then(|date| SystemTime::now().duration_since(date).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    age.max(apparent_age)
+}
+
+// Whether a client already holds the version of a resource identified by `etag`
+// and `last_modified`, given its `If-None-Match` and `If-Modified-Since` headers.
+pub fn is_not_modified(headers: &Headers, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    if let Some(if_none_match) = headers.get("if-none-match") {
+        // `If-Modified-Since` is ignored when `If-None-Match` is present.
+        let etag = etag.map(weak_etag);
+        if_none_match.split(',').map(str::trim).any(|tag| {
+            tag == "*" && etag.is_some() || etag.is_some_and(|etag| weak_etag(tag) == etag)
+        })
+    } else if let (Some(since), Some(last_modified)) =
+        (headers.get("if-modified-since"), last_modified)
+    {
+        match (
+            httpdate::parse_http_date(since),
+            httpdate::parse_http_date(last_modified),
+        ) {
+            (Ok(since), Ok(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    } else {
+        false
+    }
+}
+
+// `If-None-Match` uses the weak comparison, which ignores the `W/` prefix.
+fn weak_etag(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    static DEFAULTS: Freshness = Freshness {
+        ttl: 30,
+        stale_while_revalidate: 5,
+        stale_if_error: 10,
+    };
+
+    fn mk_headers(fields: &[(&str, &str)]) -> Headers {
+        fields
+            .iter()
+            .map(|(key, val)| (key.to_string(), val.to_string()))
+            .collect()
+    }
+
+    fn http_date(now: SystemTime, offset_secs: u64) -> String {
+        httpdate::fmt_http_date(now + Duration::from_secs(offset_secs))
+    }
+
+    #[test]
+    fn s_maxage_overrides_max_age() {
+        let headers = mk_headers(&[("cache-control", "max-age=60, s-maxage=120")]);
+        assert_eq!(freshness(&headers, &DEFAULTS).unwrap().ttl, 120);
+
+        let headers = mk_headers(&[("cache-control", "max-age=60")]);
+        assert_eq!(freshness(&headers, &DEFAULTS).unwrap().ttl, 60);
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        // A service whose clock is ahead of the proxy's.
+        let now = SystemTime::now();
+        let headers = mk_headers(&[
+            ("date", &http_date(now, 1000)),
+            ("expires", &http_date(now, 1600)),
+        ]);
+        assert_eq!(freshness(&headers, &DEFAULTS).unwrap().ttl, 600);
+
+        let headers = mk_headers(&[("date", &http_date(now, 1000)), ("expires", "0")]);
+        assert_eq!(freshness(&headers, &DEFAULTS).unwrap().ttl, 0);
+
+        let headers = mk_headers(&[
+            ("cache-control", "max-age=60"),
+            ("expires", &http_date(now, 1600)),
+        ]);
+        assert_eq!(freshness(&headers, &DEFAULTS).unwrap().ttl, 60);
+    }
+
+    #[test]
+    fn pragma_only_applies_without_cache_control() {
+        let headers = mk_headers(&[("pragma", "no-cache")]);
+        let fresh = freshness(&headers, &DEFAULTS).unwrap();
+        assert_eq!(fresh.ttl, 0);
+        assert_eq!(fresh.stale_while_revalidate, 0);
+        assert_eq!(fresh.stale_if_error, 0);
+
+        let headers = mk_headers(&[("pragma", "no-cache"), ("cache-control", "max-age=60")]);
+        let fresh = freshness(&headers, &DEFAULTS).unwrap();
+        assert_eq!(fresh.ttl, 60);
+        assert_eq!(fresh.stale_while_revalidate, 5);
+    }
+
+    #[test]
+    fn defaults_apply_without_explicit_values() {
+        let fresh = freshness(&Headers::new(), &DEFAULTS).unwrap();
+        assert_eq!(fresh.ttl, 30);
+        assert_eq!(fresh.stale_if_error, 10);
+
+        let headers = mk_headers(&[("cache-control", "private, max-age=60")]);
+        assert!(freshness(&headers, &DEFAULTS).is_none());
+        let headers = mk_headers(&[("cache-control", "no-store")]);
+        assert!(freshness(&headers, &DEFAULTS).is_none());
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::{Context, Error, Result};
+use mime::Mime;
+use regex::Regex;
+use url::Url;
+
+use crate::http::cache_control::Freshness;
+use crate::http::request::{Method, Request};
+use crate::http::response::{Response, ResponseBody, ResponseHeader};
+use crate::opts;
+
+// Decides which requests can be served from the cache and which responses are
+// stored in it. The first rule matching the path and method of a request is the
+// one applied; requests matching none are never cached.
+pub struct CacheRules {
+    rules: Vec<CacheRule>,
+}
+
+pub struct CacheRule {
+    path: Regex,
+    content_types: Vec<Mime>,
+    methods: Vec<Method>,
+    status_codes: Vec<u16>,
+    ttl: Option<u64>,
+    max_size: u64,
+}
+
+impl CacheRules {
+    pub fn new(rules: &[opts::CacheRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(CacheRule::new)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(CacheRules { rules })
+    }
+
+    pub fn find(&self, req: &Request) -> Option<&CacheRule> {
+        let method = &req.header.metadata.method;
+        let path = Url::parse("http://host")
+            .and_then(|base| base.join(&req.header.metadata.uri))
+            .ok()?;
+
+        self.rules
+            .iter()
+            .find(|rule| rule.methods.contains(method) && rule.path.is_match(path.path()))
+    }
+}
+
+impl CacheRule {
+    fn new(rule: &opts::CacheRule) -> Result<Self> {
+        let path =
+            Regex::new(&rule.path).context(format!("Invalid cache rule path: {}", rule.path))?;
+        let content_types = rule
+            .content_types
+            .iter()
+            .map(|ct| {
+                ct.parse()
+                    .context(format!("Invalid cache rule content type: {ct}"))
+            })
+            .collect::<Result<Vec<Mime>>>()?;
+        // `HEAD` requests are answered from the entries of `GET` requests, other
+        // methods are never cached.
+        let methods = rule
+            .methods
+            .iter()
+            .map(|method| match method.to_uppercase().as_str() {
+                "GET" => Ok(Method::Get),
+                "HEAD" => Ok(Method::Head),
+                _ => Err(Error::msg(format!("Invalid cache rule method: {method}"))),
+            })
+            .collect::<Result<Vec<Method>>>()?;
+
+        // A `206` only holds part of the resource, so it is never stored as if it
+        // were the whole of it.
+        let status_codes = rule
+            .status_codes
+            .iter()
+            .copied()
+            .filter(|code| *code != 206)
+            .collect();
+
+        Ok(CacheRule {
+            path,
+            content_types,
+            methods,
+            status_codes,
+            ttl: rule.ttl_secs,
+            max_size: rule.max_size_mb * 1024 * 1024,
+        })
+    }
+
+    pub fn allows(&self, res: &Response) -> bool {
+        let fits = match &res.body {
+            ResponseBody::Full(body) => body.len() as u64 <= self.max_size,
+            ResponseBody::Stream(_) => false,
+        };
+
+        fits && self.allows_header(&res.header)
+    }
+
+    // Whether a response could be cached, before its body is known.
+    pub fn allows_header(&self, header: &ResponseHeader) -> bool {
+        let status_code = header.status.code.as_u16();
+        let content_type = header
+            .get_content_type()
+            .and_then(|ct| ct.parse::<Mime>().ok());
+
+        self.status_codes.contains(&status_code)
+            && content_type.is_some_and(|ct| {
+                self.content_types
+                    .iter()
+                    .any(|range| media_type_matches(range, &ct))
+            })
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    // The rule's TTL replaces the default one.
+    pub fn freshness_defaults(&self, defaults: &Freshness) -> Freshness {
+        Freshness {
+            ttl: self.ttl.unwrap_or(defaults.ttl),
+            ..*defaults
+        }
+    }
+}
+
+// Media types are compared without their parameters, and a range such as
+// `image/*` or `*/*` matches any subtype.
+fn media_type_matches(range: &Mime, media_type: &Mime) -> bool {
+    (range.type_() == mime::STAR || range.type_() == media_type.type_())
+        && (range.subtype() == mime::STAR
+            || (range.subtype() == media_type.subtype() && range.suffix() == media_type.suffix()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(range: &str, media_type: &str) -> bool {
+        media_type_matches(&range.parse().unwrap(), &media_type.parse().unwrap())
+    }
+
+    #[test]
+    fn media_type_ranges() {
+        assert!(matches("text/html", "text/html; charset=utf-8"));
+        assert!(matches("image/*", "image/png"));
+        assert!(matches("*/*", "application/json"));
+        assert!(matches(
+            "application/vnd.api+json",
+            "application/vnd.api+json"
+        ));
+
+        assert!(!matches("image/*", "text/html"));
+        assert!(!matches("text/html", "text/plain"));
+        assert!(!matches("application/vnd.api+json", "application/vnd.api"));
+    }
+}
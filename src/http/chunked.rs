@@ -0,0 +1,293 @@
+use anyhow::{Context, Error, Result};
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::http::headers::{self, Headers};
+
+pub static CHUNK_SIZE: usize = 16384;
+static MAX_LINE_LEN: usize = 8192;
+
+// Header fields that must not be merged from a trailer section (RFC 7230 4.1.2).
+static FORBIDDEN_TRAILERS: [&str; 11] = [
+    "transfer-encoding",
+    "content-length",
+    "content-type",
+    "content-encoding",
+    "content-range",
+    "host",
+    "trailer",
+    "authorization",
+    "set-cookie",
+    "cache-control",
+    "te",
+];
+
+pub fn is_chunked(headers: &Headers) -> bool {
+    if let Some(te) = headers.get("transfer-encoding") {
+        te.split(',')
+            .next_back()
+            .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+// Reads the size line that starts a chunk. The last chunk has size zero.
+pub async fn read_chunk_size<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<usize> {
+    parse_chunk_size(read_line(reader).await?.as_str())
+}
+
+// Reads the CRLF that ends the data of a chunk.
+pub async fn read_chunk_end<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<()> {
+    if read_line(reader).await?.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::msg("Missing CRLF after chunk data"))
+    }
+}
+
+// Reads the trailer section that follows the last chunk.
+pub async fn read_trailers<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Headers> {
+    let mut trailers = Headers::new();
+    loop {
+        let line = read_line(reader).await?;
+        if line.is_empty() {
+            break;
+        }
+        let (key, val) = headers::parse_header(line.as_str())?;
+        if !FORBIDDEN_TRAILERS.contains(&key.as_str()) {
+            trailers.insert(key, val.to_string());
+        }
+    }
+
+    Ok(trailers)
+}
+
+pub fn encode(data: &[u8], chunk_size: usize, trailers: &Headers) -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(data.len() + 32);
+
+    for chunk in data.chunks(chunk_size.max(1)) {
+        buffer.append(&mut format!("{:x}\r\n", chunk.len()).into_bytes());
+        buffer.extend_from_slice(chunk);
+        buffer.extend_from_slice(b"\r\n");
+    }
+
+    buffer.extend_from_slice(b"0\r\n");
+    for (key, value) in trailers.iter() {
+        buffer.append(&mut format!("{key}:{value}\r\n").into_bytes());
+    }
+    buffer.extend_from_slice(b"\r\n");
+
+    buffer
+}
+
+// The chunked coding is the only transfer coding the proxy can decode, messages
+// with any other are rejected (RFC 9112 6.1).
+pub fn has_other_codings(headers: &Headers) -> bool {
+    headers.get("transfer-encoding").is_some_and(|te| {
+        te.split(',')
+            .map(str::trim)
+            .any(|c| !c.is_empty() && !c.eq_ignore_ascii_case("chunked"))
+    })
+}
+
+// Turns the headers of a de-chunked message into the ones of an equivalent
+// length-delimited message.
+pub fn dechunk_headers(headers: &mut Headers, body_len: usize, trailers: Headers) {
+    headers.remove("transfer-encoding");
+    headers.remove("trailer");
+    for (key, value) in trailers {
+        headers.insert(key, value);
+    }
+    headers.insert("content-length".to_string(), body_len.to_string());
+}
+
+fn parse_chunk_size(line: &str) -> Result<usize> {
+    // Chunk extensions are not interpreted by the proxy, they are only validated and dropped.
+    let (size, exts) = line.split_once(';').unwrap_or((line, ""));
+    for ext in exts.split(';').filter(|e| !e.trim().is_empty()) {
+        let name = ext.split_once('=').map(|(n, _)| n).unwrap_or(ext).trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(Error::msg(format!("Invalid chunk extension: {:?}", ext)));
+        }
+    }
+
+    usize::from_str_radix(size.trim(), 16).context(format!("Invalid chunk size: {:?}", line))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut line: Vec<u8> = Vec::new();
+    reader
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(0x0A, &mut line)
+        .await
+        .context("Error while reading chunked body")?;
+
+    match line.pop() {
+        Some(0x0A) => {}
+        _ if line.len() >= MAX_LINE_LEN => return Err(Error::msg("Chunk line too long")),
+        _ => {
+            return Err(Error::msg(
+                "Connection closed in the middle of a chunked body",
+            ))
+        }
+    }
+    if line.last() == Some(&0x0D) {
+        line.pop();
+    }
+
+    Ok(String::from_utf8(line)?)
+}
+
+// Encodes the data written to it as chunks, for bodies whose length is not known
+// in advance.
+pub struct ChunkedWriter<W: AsyncWrite + Unpin> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    // Writes `buf` as a single chunk.
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        // An empty chunk would end the body.
+        if !buf.is_empty() {
+            self.inner
+                .write_all(format!("{:x}\r\n", buf.len()).as_bytes())
+                .await?;
+            self.inner.write_all(buf).await?;
+            self.inner.write_all(b"\r\n").await?;
+        }
+        Ok(())
+    }
+
+    // Writes the last chunk and the trailer section.
+    pub async fn finish(mut self, trailers: &Headers) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n").await?;
+        for (key, value) in trailers.iter() {
+            self.inner
+                .write_all(format!("{key}:{value}\r\n").as_bytes())
+                .await?;
+        }
+        self.inner.write_all(b"\r\n").await?;
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn chunk_extensions_are_dropped() {
+        assert_eq!(parse_chunk_size("1a").unwrap(), 26);
+        assert_eq!(parse_chunk_size("1A;name=value;flag").unwrap(), 26);
+        assert_eq!(parse_chunk_size("5 ; name = \"quoted\"").unwrap(), 5);
+        assert!(parse_chunk_size("5;=value").is_err());
+        assert!(parse_chunk_size("5;bad name").is_err());
+        assert!(parse_chunk_size("xyz").is_err());
+    }
+
+    #[test]
+    fn forbidden_trailers_are_dropped() {
+        let mut input: &[u8] =
+            b"X-Done: yes\r\nContent-Length: 5\r\nSet-Cookie: a=b\r\nHost: evil\r\n\r\nnext";
+        let trailers = block_on(read_trailers(&mut input)).unwrap();
+
+        assert_eq!(trailers.len(), 1);
+        assert_eq!(trailers.get("x-done").map(String::as_str), Some("yes"));
+        assert_eq!(input, b"next");
+    }
+
+    #[test]
+    fn missing_crlf_after_chunk_data() {
+        let mut input: &[u8] = b"\r\n";
+        assert!(block_on(read_chunk_end(&mut input)).is_ok());
+
+        let mut input: &[u8] = b"extra\r\n";
+        assert!(block_on(read_chunk_end(&mut input)).is_err());
+
+        let mut input: &[u8] = b"";
+        assert!(block_on(read_chunk_end(&mut input)).is_err());
+    }
+
+    #[test]
+    fn over_long_size_line() {
+        let line = format!("{}\r\n", "0".repeat(MAX_LINE_LEN + 10));
+        let mut input = line.as_bytes();
+        let err = block_on(read_chunk_size(&mut input)).unwrap_err();
+
+        assert_eq!(err.to_string(), "Chunk line too long");
+    }
+
+    #[test]
+    fn chunked_must_be_the_last_coding() {
+        let mut headers = Headers::new();
+        headers.insert("transfer-encoding".to_string(), "gzip, chunked".to_string());
+        assert!(is_chunked(&headers));
+        assert!(has_other_codings(&headers));
+
+        headers.insert("transfer-encoding".to_string(), "chunked, gzip".to_string());
+        assert!(!is_chunked(&headers));
+
+        headers.insert("transfer-encoding".to_string(), "Chunked".to_string());
+        assert!(is_chunked(&headers));
+        assert!(!has_other_codings(&headers));
+    }
+
+    #[test]
+    fn dechunked_headers_have_a_length() {
+        let mut headers = Headers::new();
+        headers.insert("transfer-encoding".to_string(), "chunked".to_string());
+        headers.insert("trailer".to_string(), "x-done".to_string());
+
+        let mut trailers = Headers::new();
+        trailers.insert("x-done".to_string(), "yes".to_string());
+        dechunk_headers(&mut headers, 42, trailers);
+
+        assert!(!headers.contains_key("transfer-encoding"));
+        assert!(!headers.contains_key("trailer"));
+        assert_eq!(
+            headers.get("content-length").map(String::as_str),
+            Some("42")
+        );
+        assert_eq!(headers.get("x-done").map(String::as_str), Some("yes"));
+    }
+
+    #[test]
+    fn encoded_chunks_can_be_read_back() {
+        let mut trailers = Headers::new();
+        trailers.insert("x-done".to_string(), "yes".to_string());
+        let encoded = encode(b"hello world", 4, &trailers);
+        let mut input = encoded.as_slice();
+
+        let mut data = Vec::new();
+        block_on(async {
+            loop {
+                let size = read_chunk_size(&mut input).await.unwrap();
+                if size == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; size];
+                input.read_exact(&mut chunk).await.unwrap();
+                data.extend_from_slice(&chunk);
+                read_chunk_end(&mut input).await.unwrap();
+            }
+            assert_eq!(read_trailers(&mut input).await.unwrap(), trailers);
+        });
+
+        assert_eq!(data, b"hello world");
+        assert!(input.is_empty());
+    }
+}
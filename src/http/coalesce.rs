@@ -0,0 +1,137 @@
+use log::error;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::http::headers::Headers;
+use crate::http::response::Response;
+
+// Collapses concurrent requests for the same resource, so that only the first one
+// (the leader) is sent to the services while the rest (the followers) wait for
+// its response.
+#[derive(Clone, Default)]
+pub struct Coalescer {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+pub enum Role {
+    Leader(Leader),
+    Follower(Follower),
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Coalescer::default()
+    }
+
+    pub fn join(&self, key: &str, req_headers: &Headers) -> Role {
+        if let Ok(mut flights) = self.flights.lock() {
+            if let Some(flight) = flights.get(key) {
+                return Role::Follower(Follower {
+                    flight: flight.clone(),
+                });
+            }
+
+            let flight = Arc::new(Flight::new(req_headers.clone()));
+            flights.insert(key.to_string(), flight.clone());
+            Role::Leader(Leader {
+                key: Some(key.to_string()),
+                flight,
+                coalescer: self.clone(),
+            })
+        } else {
+            // Without the lock the request is sent on its own.
+            error!("Coalescer: Failed to get lock");
+            Role::Leader(Leader {
+                key: None,
+                flight: Arc::new(Flight::new(Headers::new())),
+                coalescer: self.clone(),
+            })
+        }
+    }
+}
+
+struct Flight {
+    req_headers: Headers,
+    // `None` while the leader's request is in progress, then the response if it
+    // can be shared.
+    res: watch::Sender<Option<Option<Response>>>,
+}
+
+impl Flight {
+    fn new(req_headers: Headers) -> Self {
+        Flight {
+            req_headers,
+            res: watch::Sender::new(None),
+        }
+    }
+
+    fn complete(&self, res: Option<Response>) {
+        self.res.send_if_modified(|slot| {
+            if slot.is_none() {
+                *slot = Some(res);
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+pub struct Leader {
+    key: Option<String>,
+    flight: Arc<Flight>,
+    coalescer: Coalescer,
+}
+
+impl Leader {
+    // Hands the response to the followers. `None` makes them send their own
+    // requests, e.g. when the response must not be shared.
+    pub fn finish(self, res: Option<Response>) {
+        self.flight.complete(res);
+    }
+}
+
+// The followers are released even if the leader fails before finishing.
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.flight.complete(None);
+        if let Some(key) = &self.key {
+            if let Ok(mut flights) = self.coalescer.flights.lock() {
+                flights.remove(key);
+            }
+        }
+    }
+}
+
+pub struct Follower {
+    flight: Arc<Flight>,
+}
+
+impl Follower {
+    // Waits for the leader's response, which is only returned if it is the same
+    // variant of the resource the follower asked for.
+    pub async fn wait(&self, timeout: Duration, req_headers: &Headers) -> Option<Response> {
+        let mut done = self.flight.res.subscribe();
+        let res = {
+            let slot = tokio::time::timeout(timeout, done.wait_for(|res| res.is_some()))
+                .await
+                .ok()?
+                .ok()?;
+            slot.as_ref()?.as_ref()?.try_clone()?
+        };
+
+        match res.header.headers.get("vary") {
+            Some(vary) if !same_variant(vary, &self.flight.req_headers, req_headers) => None,
+            _ => Some(res),
+        }
+    }
+}
+
+fn same_variant(vary: &str, a: &Headers, b: &Headers) -> bool {
+    vary.split(',').all(|name| {
+        let name = name.trim().to_lowercase();
+        a.get(&name).map(|v| v.trim()) == b.get(&name).map(|v| v.trim())
+    })
+}
@@ -1,150 +1,865 @@
-use anyhow::{Error, Result};
+use anyhow::{Context, Error, Result};
 use log::{error, info, warn};
-use std::net::TcpStream;
+use std::future::{poll_fn, Future};
+use std::net::IpAddr;
+use std::pin::pin;
 use std::sync::mpsc::Sender;
-use std::time;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{self, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_io_timeout::TimeoutReader;
 
-use crate::cache::io::{mk_file_path, CacheFile};
-use crate::concurrent::ccfifo_queue::CCFifoQueue;
+use crate::balancer::health::ServiceHealth;
+use crate::balancer::{Balancer, PickGuard, RequestContext};
+use crate::cache::io::{mk_file_path, mk_variant_path, CacheFile, FileMetadata};
+use crate::cache::memory::MemoryCache;
 use crate::http::{
-    request::{Method, Request},
-    response::Response,
+    body::{BodyAborted, BodyReader, BodyStream, Framing},
+    cache_control::{self, Freshness},
+    cache_rules::CacheRules,
+    chunked,
+    coalesce::{Coalescer, Leader, Role},
+    headers, range,
+    request::{Method, Rejected, Request},
+    response::{Code, Response, ResponseBody, ResponseHeader},
+    upstream::{UpstreamConn, UpstreamPool},
 };
 use crate::opts::Service;
 use std::path::PathBuf;
 
-pub fn http_handler(
-    client_stream: TcpStream,
-    cache_dir: PathBuf,
-    cache_ttl: u64,
-    cache_sender: Sender<CacheFile>,
-    addr_queue: CCFifoQueue<Service>,
-    failure_delay: u64,
-    failure_retries: u16,
-) {
-    match Request::read(&client_stream) {
-        Ok(mut req) => {
-            req.header.pretty_log();
-            if let Ok(lock) = addr_queue.poller.lock() {
-                if let Ok(service) = lock.recv() {
-                    drop(lock);
-                    addr_queue.pusher.send(service.clone()).unwrap();
-
-                    let is_get_req = req.header.metadata.method == Method::Get;
-                    let file_path = mk_file_path(&cache_dir, req.header.metadata.uri.clone());
-                    let proxy_pass = || {
-                        proxy_pass(
-                            service,
-                            &mut req,
-                            &client_stream,
-                            &cache_dir,
-                            cache_sender,
-                            cache_ttl,
-                            is_get_req,
-                            failure_delay,
-                            failure_retries,
-                        )
-                    };
-
-                    match (is_get_req, file_path.as_path().is_file()) {
-                        (true, true) => {
-                            if let Ok(metadata) = CacheFile::read_header(&file_path) {
-                                if !metadata.is_expired() {
-                                    if let Ok(cache_file) = CacheFile::read(file_path, metadata) {
-                                        info!("Retrieving resource from cache");
-                                        let mut res = Response::from_cache_file(cache_file);
-                                        res.write(&client_stream)
-                                    } else {
-                                        proxy_pass();
-                                    }
-                                } else {
-                                    proxy_pass();
-                                }
-                            } else {
-                                warn!("Failed to read cache file metadata");
-                                proxy_pass();
-                            }
-                        }
-                        _ => proxy_pass(),
+static LINGER_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct ProxyContext {
+    pub cache_dir: PathBuf,
+    pub cache_freshness: Freshness,
+    pub cache_rules: Arc<CacheRules>,
+    pub cache_sender: Sender<CacheFile>,
+    pub memory_cache: MemoryCache,
+    pub coalescer: Coalescer,
+    pub balancer: Arc<dyn Balancer>,
+    pub failure_delay: u64,
+    pub failure_retries: u16,
+    pub failover_deadline: u64,
+    pub health: ServiceHealth,
+    pub keep_alive_timeout: u64,
+    pub keep_alive_max_requests: u32,
+    pub max_request_body_size: u64,
+    pub upstream_pool: UpstreamPool,
+}
+
+pub async fn http_handler(client_stream: TcpStream, ctx: ProxyContext) {
+    let timeout = time::Duration::from_secs(ctx.keep_alive_timeout);
+    let client_ip = client_stream.peer_addr().ok().map(|addr| addr.ip());
+    let (reader, mut writer) = client_stream.into_split();
+    let mut reader = TimeoutReader::new(reader);
+    reader.set_timeout(Some(timeout));
+    let mut reader = BufReader::new(Box::pin(reader));
+    let mut served: u32 = 0;
+
+    loop {
+        // An idle connection is dropped once the client closes it or the keep-alive timeout expires.
+        match reader.fill_buf().await {
+            Ok(buff) if !buff.is_empty() => {}
+            _ => break,
+        }
+
+        match Request::read_streaming(&mut reader, ctx.max_request_body_size).await {
+            Ok((mut req, pump)) => {
+                served += 1;
+                req.header.pretty_log();
+
+                let mut keep_alive = req.header.is_keep_alive()
+                    && !req.must_close
+                    && served < ctx.keep_alive_max_requests;
+                headers::remove_hop_by_hop(&mut req.header.headers);
+
+                // A streamed body is read from the client while the request is sent.
+                let (mut res, pumped) = match pump {
+                    Some(pump) => {
+                        let handle = async {
+                            let res = handle_request(&mut req, &ctx, client_ip).await;
+                            req.body.close();
+                            res
+                        };
+                        join(handle, pump.run()).await
+                    }
+                    None => (handle_request(&mut req, &ctx, client_ip).await, Ok(())),
+                };
+                // The rest of a body that was not read whole can't be told apart
+                // from the next request.
+                if let Err(err) = &pumped {
+                    warn!("http_handler: {err:#}");
+                    if err.downcast_ref::<Rejected>().is_some() {
+                        res = error_response(err);
                     }
+                    keep_alive = false;
+                }
+
+                set_connection_headers(&mut res, keep_alive, &ctx, served);
+                if let Err(err) = res.write(&mut writer).await {
+                    warn!("http_handler: {err:#}");
+                    break;
+                }
+
+                if pumped.is_err() {
+                    lingering_close(&mut reader, &mut writer).await;
+                }
+                if !keep_alive {
+                    break;
+                }
+            }
+            Err(err) => {
+                let mut res = error_response(&err);
+                set_connection_headers(&mut res, false, &ctx, served);
+                if let Err(err) = res.write(&mut writer).await {
+                    warn!("http_handler: {err:#}");
+                }
+                lingering_close(&mut reader, &mut writer).await;
+                break;
+            }
+        }
+    }
+}
+
+// Requests the proxy won't serve get their own status, and any other request it
+// could not read a `400`.
+fn error_response(err: &Error) -> Response {
+    match err.downcast_ref::<Rejected>() {
+        Some(rejected) => Response::plain_text(
+            rejected.code.clone(),
+            rejected.reason,
+            format!("{}\n", rejected.reason),
+        ),
+        None => Response::response400(),
+    }
+}
+
+// Runs both futures on the current task until they have both completed.
+async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    let (mut a_out, mut b_out) = (None, None);
+    poll_fn(|cx| {
+        if a_out.is_none() {
+            if let Poll::Ready(out) = a.as_mut().poll(cx) {
+                a_out = Some(out);
+            }
+        }
+        if b_out.is_none() {
+            if let Poll::Ready(out) = b.as_mut().poll(cx) {
+                b_out = Some(out);
+            }
+        }
+        match (a_out.take(), b_out.take()) {
+            (Some(a), Some(b)) => Poll::Ready((a, b)),
+            (a, b) => {
+                (a_out, b_out) = (a, b);
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+// A socket closed with unread data resets the connection, and the client may
+// lose the response while it is still sending a rejected request. The rest of
+// the request is drained for a while instead.
+async fn lingering_close<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+) {
+    if writer.shutdown().await.is_err() {
+        return;
+    }
+    let mut buf = vec![0u8; 16384];
+    let drain = async { while matches!(reader.read(&mut buf).await, Ok(n) if n > 0) {} };
+    let _ = tokio::time::timeout(LINGER_TIMEOUT, drain).await;
+}
+
+// Range requests are answered out of complete responses whenever there is one,
+// be it a cache entry or a response of the service that ignored the range.
+async fn handle_request(
+    req: &mut Request,
+    ctx: &ProxyContext,
+    client_ip: Option<IpAddr>,
+) -> Response {
+    let range = match req.header.metadata.method {
+        Method::Get => req.header.headers.get("range").cloned(),
+        _ => None,
+    };
+    let Some(range) = range else {
+        return fetch_resource(req, ctx, client_ip).await;
+    };
+
+    // A range covering the whole resource is requested without it, so the
+    // response of the service can be cached.
+    if range::is_whole(&range) && ctx.cache_rules.find(req).is_some() {
+        req.header.remove_header("range".to_string());
+    }
+    let res = fetch_resource(req, ctx, client_ip).await;
+    req.header.insert_header("range".to_string(), range);
+
+    range::apply(&req.header.headers, res)
+}
+
+async fn fetch_resource(
+    req: &mut Request,
+    ctx: &ProxyContext,
+    client_ip: Option<IpAddr>,
+) -> Response {
+    let method = req.header.metadata.method.clone();
+    let is_get_req = method == Method::Get;
+    if ctx.cache_rules.find(req).is_none() {
+        return proxy_pass(req, ctx, client_ip, is_get_req).await;
+    }
+
+    let (file_path, metadata) = lookup_cache_entry(req, ctx).await;
+
+    match (method, metadata) {
+        (Method::Get, Some(Ok(metadata))) => {
+            if !metadata.is_expired() {
+                match serve_from_cache(req, ctx, file_path, metadata).await {
+                    Some(res) => res,
+                    None => proxy_pass(req, ctx, client_ip, is_get_req).await,
+                }
+            } else if metadata.can_serve_while_revalidating() {
+                refresh_in_background(req, ctx, client_ip, file_path.clone(), metadata.clone());
+                info!("Serving stale resource while it is revalidated");
+                match serve_from_cache(req, ctx, file_path, metadata).await {
+                    Some(res) => res,
+                    None => proxy_pass(req, ctx, client_ip, is_get_req).await,
+                }
+            } else {
+                let res = if metadata.has_validators() {
+                    revalidate(req, ctx, client_ip, file_path.clone(), metadata.clone()).await
+                } else {
+                    proxy_pass_coalesced(req, ctx, client_ip).await
+                };
+
+                if res.header.status.code.is_server_error() && metadata.can_serve_on_error() {
+                    warn!("Serving stale resource because the services failed");
+                    serve_from_cache(req, ctx, file_path, metadata)
+                        .await
+                        .unwrap_or(res)
                 } else {
-                    error!("http_handler: Failed to poll addr");
+                    res
                 }
+            }
+        }
+        (Method::Get, Some(Err(_))) => {
+            warn!("Failed to read cache file metadata");
+            proxy_pass(req, ctx, client_ip, is_get_req).await
+        }
+        (Method::Get, None) => proxy_pass_coalesced(req, ctx, client_ip).await,
+        // Expired entries are only revalidated by `GET` requests.
+        (Method::Head, Some(Ok(metadata))) if !metadata.is_expired() => {
+            match serve_from_cache(req, ctx, file_path, metadata).await {
+                Some(mut res) => {
+                    res.body = ResponseBody::Full(Vec::new());
+                    res
+                }
+                None => proxy_pass(req, ctx, client_ip, false).await,
+            }
+        }
+        _ => proxy_pass(req, ctx, client_ip, is_get_req).await,
+    }
+}
+
+// Replies with a cache entry, or with a `304` if the client already has it.
+async fn serve_from_cache(
+    req: &Request,
+    ctx: &ProxyContext,
+    file_path: PathBuf,
+    metadata: FileMetadata,
+) -> Option<Response> {
+    if is_not_modified(&req.header.headers, &metadata) {
+        info!("Resource not modified");
+        Some(Response::not_modified(&metadata))
+    } else {
+        let cache_file = read_cache_file(ctx, file_path).await.ok()?;
+        info!("Retrieving resource from cache");
+        Some(Response::from_cache_file(cache_file))
+    }
+}
+
+// Refreshes an expired cache entry without making the client wait for it. There
+// is at most one refresh of a resource in progress.
+fn refresh_in_background(
+    req: &Request,
+    ctx: &ProxyContext,
+    client_ip: Option<IpAddr>,
+    file_path: PathBuf,
+    metadata: FileMetadata,
+) {
+    let Role::Leader(leader) = ctx
+        .coalescer
+        .join(&req.header.cache_key(), &req.header.headers)
+    else {
+        return;
+    };
+
+    // The refresh must fetch the resource even if the client already has it.
+    let mut req = req.clone();
+    req.header.remove_header("if-none-match".to_string());
+    req.header.remove_header("if-modified-since".to_string());
+    let ctx = ctx.clone();
+
+    tokio::spawn(async move {
+        let mut res = if metadata.has_validators() {
+            revalidate(&mut req, &ctx, client_ip, file_path, metadata).await
+        } else {
+            proxy_pass(&mut req, &ctx, client_ip, true).await
+        };
+        share_response(leader, &req, &mut res, &ctx);
+
+        // Nobody waits for the body, but it has to be read for the entry to be
+        // cached.
+        if let ResponseBody::Stream(stream) = res.body {
+            if let Err(err) = stream.pipe(&mut tokio::io::sink()).await {
+                warn!("Failed to refresh resource: {err}");
+            }
+        }
+    });
+}
+
+// Hands the response to the followers if it can be cached. Streamed responses
+// are handed over once they have been read whole, if they were copied.
+fn share_response(leader: Leader, req: &Request, res: &mut Response, ctx: &ProxyContext) {
+    match &mut res.body {
+        ResponseBody::Full(_) => {
+            let is_shareable = cache_freshness(req, res, ctx).is_some();
+            leader.finish(is_shareable.then(|| res.try_clone()).flatten());
+        }
+        ResponseBody::Stream(stream) => {
+            let (req, ctx) = (req.clone(), ctx.clone());
+            stream.on_complete(Box::new(move |res| {
+                let is_shareable = cache_freshness(&req, res, &ctx).is_some();
+                leader.finish(is_shareable.then(|| res.try_clone()).flatten());
+            }));
+        }
+    }
+}
+
+// Concurrent misses of the same resource are collapsed into a single request to
+// the services, whose response is shared if it can be cached.
+async fn proxy_pass_coalesced(
+    req: &mut Request,
+    ctx: &ProxyContext,
+    client_ip: Option<IpAddr>,
+) -> Response {
+    match ctx
+        .coalescer
+        .join(&req.header.cache_key(), &req.header.headers)
+    {
+        Role::Leader(leader) => {
+            let mut res = proxy_pass(req, ctx, client_ip, true).await;
+            share_response(leader, req, &mut res, ctx);
+            res
+        }
+        Role::Follower(follower) => {
+            let timeout = time::Duration::from_millis(ctx.failover_deadline);
+            if let Some(res) = follower.wait(timeout, &req.header.headers).await {
+                info!("Sharing the response of a concurrent request");
+                res
             } else {
-                error!("http_handler: Failed to get lock");
+                proxy_pass(req, ctx, client_ip, true).await
             }
         }
-        Err(_) => Response::response400().write(&client_stream),
     }
 }
 
+// Path of the cache entry of a request and the metadata of the entry, if there is
+// one. If the resource varies on some request headers, the file of the URI leads
+// to the variant matching the request. The disk is only accessed on the blocking
+// pool, as in `read_cache_file`.
+async fn lookup_cache_entry(
+    req: &Request,
+    ctx: &ProxyContext,
+) -> (PathBuf, Option<Result<FileMetadata>>) {
+    let uri_path = mk_file_path(&ctx.cache_dir, &req.header.cache_key());
+    let req_headers = req.header.headers.clone();
+    let memory_cache = ctx.memory_cache.clone();
+
+    let path = uri_path.clone();
+    let lookup = tokio::task::spawn_blocking(move || {
+        let path = match memory_cache.read_header(&path) {
+            Ok(FileMetadata {
+                vary: Some(vary), ..
+            }) => mk_variant_path(&path, &vary, &req_headers),
+            _ => path,
+        };
+        let is_cached = memory_cache.contains(&path) || path.as_path().is_file();
+        let metadata = is_cached.then(|| memory_cache.read_header(&path));
+        (path, metadata)
+    });
+
+    lookup
+        .await
+        .unwrap_or_else(|err| (uri_path, Some(Err(err.into()))))
+}
+
+// Asks the service whether an expired cache entry is still valid. On a `304` the
+// entry is refreshed and served from disk, otherwise the service's response is
+// used (and cached again if possible).
+async fn revalidate(
+    req: &mut Request,
+    ctx: &ProxyContext,
+    client_ip: Option<IpAddr>,
+    file_path: PathBuf,
+    metadata: FileMetadata,
+) -> Response {
+    // The client's own validators are replaced by the ones of the cache entry.
+    let client_headers = req.header.headers.clone();
+    if let Some(etag) = &metadata.etag {
+        req.header
+            .insert_header("if-none-match".to_string(), etag.clone());
+    }
+    if let Some(last_modified) = &metadata.last_modified {
+        req.header
+            .insert_header("if-modified-since".to_string(), last_modified.clone());
+    }
+
+    let res = proxy_pass(req, ctx, client_ip, true).await;
+    req.header.headers = client_headers;
+    if !matches!(res.header.status.code, Code::Code304) {
+        return res;
+    }
+
+    match read_cache_file(ctx, file_path).await {
+        Ok(mut cache_file) => {
+            let defaults = ctx
+                .cache_rules
+                .find(req)
+                .map_or(ctx.cache_freshness, |rule| {
+                    rule.freshness_defaults(&ctx.cache_freshness)
+                });
+            if let Some(freshness) = cache_control::freshness(&res.header.headers, &defaults) {
+                if cache_file
+                    .metadata
+                    .refresh(freshness, &res.header.headers)
+                    .is_ok()
+                    && ctx.cache_sender.send(cache_file.clone()).is_err()
+                {
+                    error!("Failed to queue cache file");
+                }
+            }
+
+            if is_not_modified(&req.header.headers, &cache_file.metadata) {
+                info!("Resource revalidated and not modified");
+                Response::not_modified(&cache_file.metadata)
+            } else {
+                info!("Resource revalidated, retrieving it from cache");
+                Response::from_cache_file(cache_file)
+            }
+        }
+        Err(err) => {
+            // The entry is gone, so the request has to be sent again as the client did.
+            warn!("{err}");
+            proxy_pass(req, ctx, client_ip, true).await
+        }
+    }
+}
+
+// Cache files are read on the blocking pool, so that a slow disk does not stall
+// the other connections of the worker.
+async fn read_cache_file(ctx: &ProxyContext, file_path: PathBuf) -> Result<CacheFile> {
+    let memory_cache = ctx.memory_cache.clone();
+    tokio::task::spawn_blocking(move || memory_cache.read(file_path)).await?
+}
+
+fn is_not_modified(req_headers: &headers::Headers, metadata: &FileMetadata) -> bool {
+    cache_control::is_not_modified(
+        req_headers,
+        metadata.etag.as_deref(),
+        metadata.last_modified.as_deref(),
+    )
+}
+
+// How far a request got before its service failed, which decides whether it can
+// be sent to another service.
+enum UpstreamError {
+    Connect(Error),
+    NoResponse(Error),
+    BadResponse(Error),
+    // The client did not send the whole body of a streamed request, which is not
+    // a failure of the service.
+    BodyAborted(Error),
+}
+
+impl UpstreamError {
+    fn can_failover(&self, req_method: &Method) -> bool {
+        match self {
+            UpstreamError::Connect(_) => true,
+            UpstreamError::NoResponse(_) => req_method.is_idempotent(),
+            UpstreamError::BadResponse(_) | UpstreamError::BodyAborted(_) => false,
+        }
+    }
+
+    fn error(&self) -> &Error {
+        match self {
+            UpstreamError::Connect(err)
+            | UpstreamError::NoResponse(err)
+            | UpstreamError::BadResponse(err)
+            | UpstreamError::BodyAborted(err) => err,
+        }
+    }
+}
+
+// Sends the request to the service picked by the balancer. When a service fails,
+// the request moves on to another one that has not been tried yet, up to
+// `failure_retries` times and within `failover_deadline`. Once every service has
+// been tried, the next round starts after `failure_delay`.
 #[inline(always)]
-fn proxy_pass(
-    service: Service,
+async fn proxy_pass(
     req: &mut Request,
-    client_stream: &TcpStream,
-    cache_dir: &PathBuf,
-    cache_sender: Sender<CacheFile>,
-    cache_ttl: u64,
+    ctx: &ProxyContext,
+    client_ip: Option<IpAddr>,
     is_get_req: bool,
-    failure_delay: u64,
-    failure_retries: u16,
-) {
-    info!("Proxy passing");
-    let host = format!("{}:{}", service.addr, service.port);
-    let service_stream = connect_to_service(service, failure_delay, failure_retries);
-    match service_stream {
-        Ok(service_stream) => {
-            req.write(&service_stream, host);
-            match Response::read(&service_stream) {
-                Ok(mut res) => {
-                    res.header.pretty_log();
-                    if is_get_req && res.is_cacheable() {
-                        if let Ok(cache_file) = CacheFile::new(
-                            cache_ttl,
-                            res.body.len() as u64,
-                            mk_file_path(cache_dir, req.header.metadata.uri.clone()),
-                            res.body.clone(),
-                            res.get_content_type(),
-                        ) {
-                            if let Err(_) = cache_sender.send(cache_file) {
-                                error!("Failed to queue cache file");
-                            }
-                        } else {
-                            error!("Failed to cache resource file");
-                        }
-                    }
+) -> Response {
+    let deadline = Instant::now() + time::Duration::from_millis(ctx.failover_deadline);
+    let mut tried: Vec<Service> = Vec::new();
+    let mut retries: u16 = 0;
+
+    loop {
+        let target = RequestContext {
+            client_ip,
+            header: &req.header,
+            excluded: &tried,
+        };
 
-                    res.write(&client_stream);
+        let service = match ctx.balancer.pick(&target) {
+            Some(service) => service,
+            None if !tried.is_empty() => {
+                let delay = time::Duration::from_millis(ctx.failure_delay);
+                if Instant::now() + delay >= deadline {
+                    error!("Failover deadline exceeded");
+                    return Response::response500();
                 }
-                Err(_) => {
-                    error!("Failed to parse server response");
-                    Response::response500().write(&client_stream);
+                tokio::time::sleep(delay).await;
+                tried.clear();
+                continue;
+            }
+            None => {
+                error!("http_handler: No service available");
+                return Response::response500();
+            }
+        };
+
+        let guard = PickGuard::new(ctx.balancer.clone(), service.clone());
+        let result = proxy_pass_to(&service, req, ctx, is_get_req, deadline).await;
+
+        match result {
+            Ok(mut res) => {
+                if let ResponseBody::Stream(stream) = &mut res.body {
+                    stream.set_guard(guard);
+                }
+                if res.header.status.code.is_server_error() {
+                    ctx.health.record_failure(&service);
+                } else {
+                    ctx.health.record_success(&service);
+                }
+                return res;
+            }
+            Err(UpstreamError::BodyAborted(err)) => {
+                warn!("{}: {:#}", service.host(), err);
+                return Response::response400();
+            }
+            Err(err) => {
+                error!("{}: {:#}", service.host(), err.error());
+                ctx.health.record_failure(&service);
+
+                if !err.can_failover(&req.header.metadata.method)
+                    || retries >= ctx.failure_retries
+                    || Instant::now() >= deadline
+                {
+                    return Response::response500();
                 }
-            };
+
+                retries += 1;
+                warn!("Failing over from {} (retry {})", service.host(), retries);
+                tried.push(service);
+            }
         }
-        Err(err) => {
-            error!("{}", err);
-            Response::response500().write(&client_stream);
+    }
+}
+
+#[inline(always)]
+async fn proxy_pass_to(
+    service: &Service,
+    req: &mut Request,
+    ctx: &ProxyContext,
+    is_get_req: bool,
+    deadline: Instant,
+) -> std::result::Result<Response, UpstreamError> {
+    info!("Proxy passing");
+    req.header
+        .insert_header("connection".to_string(), "keep-alive".to_string());
+
+    let (mut header, conn) = send_request(service, req, ctx, deadline).await?;
+    header.pretty_log();
+    let body = read_body(service, req, &mut header, conn, ctx, is_get_req).await?;
+    headers::remove_hop_by_hop(&mut header.headers);
+    let mut res = Response { header, body };
+
+    if is_get_req {
+        match &mut res.body {
+            ResponseBody::Full(_) => store_response(req, &res, ctx),
+            // A copied body is cached once it has been sent to the client.
+            ResponseBody::Stream(stream) => {
+                let (req, ctx) = (req.clone(), ctx.clone());
+                stream.on_complete(Box::new(move |res| store_response(&req, res, &ctx)));
+            }
         }
     }
+
+    Ok(res)
 }
 
+fn store_response(req: &Request, res: &Response, ctx: &ProxyContext) {
+    if let Some(freshness) = cache_freshness(req, res, ctx) {
+        if let Err(err) = cache_response(req, res, freshness, ctx) {
+            error!("Failed to cache resource file: {err:#}");
+        }
+    }
+}
+
+// Bodies are streamed to the client as they arrive, so large responses are never
+// held in memory. Those that may be cached are also copied as they are sent, as
+// long as they fit in the cache rule's size limit. Range requests are the
+// exception: their ranges are cut out of the whole body, so it is read first.
+async fn read_body(
+    service: &Service,
+    req: &Request,
+    header: &mut ResponseHeader,
+    conn: UpstreamConn,
+    ctx: &ProxyContext,
+    is_get_req: bool,
+) -> std::result::Result<ResponseBody, UpstreamError> {
+    let keep_alive = header.is_keep_alive();
+    if !header.has_body(&req.header.metadata.method) {
+        // A connection that switched protocols cannot carry more requests.
+        if keep_alive && !matches!(header.status.code, Code::Code101) {
+            ctx.upstream_pool.put(service, conn);
+        }
+        return Ok(ResponseBody::Full(Vec::new()));
+    }
+
+    if chunked::has_other_codings(&header.headers) {
+        return Err(UpstreamError::BadResponse(Error::msg(
+            "Unsupported transfer coding in server response",
+        )));
+    }
+
+    let framing = Framing::from_headers(&header.headers).unwrap_or(Framing::UntilClose);
+    let pool = (keep_alive && framing != Framing::UntilClose)
+        .then(|| (ctx.upstream_pool.clone(), service.clone()));
+    let mut reader = BodyReader::new(conn, framing);
+
+    let max_size = ctx
+        .cache_rules
+        .find(req)
+        .filter(|rule| is_get_req && header.cache_freshness(rule, &ctx.cache_freshness).is_some())
+        .map(|rule| rule.max_size())
+        .filter(|max_size| !matches!(framing, Framing::Length(len) if len > *max_size));
+
+    let is_range_req = req.header.headers.contains_key("range");
+    let mut prefix = Vec::new();
+    if let Some(max_size) = max_size.filter(|_| is_range_req) {
+        reader
+            .read_to_end(&mut prefix, max_size)
+            .await
+            .context("Error while reading response")
+            .map_err(UpstreamError::BadResponse)?;
+
+        if reader.is_done() {
+            reader.set_length(&mut header.headers, prefix.len());
+            if let Some((pool, service)) = pool {
+                pool.put(&service, reader.into_inner());
+            }
+            return Ok(ResponseBody::Full(prefix));
+        }
+    }
+
+    let mut stream = BodyStream::new(prefix, reader, pool);
+    if let Some(max_size) = max_size.filter(|_| !is_range_req) {
+        let mut tee_header = header.clone();
+        headers::remove_hop_by_hop(&mut tee_header.headers);
+        stream.tee(tee_header, max_size);
+    }
+    Ok(ResponseBody::Stream(Box::new(stream)))
+}
+
+// How long a response can be cached, according to the cache rule of its request.
+fn cache_freshness(req: &Request, res: &Response, ctx: &ProxyContext) -> Option<Freshness> {
+    let rule = ctx.cache_rules.find(req)?;
+    res.cache_freshness(rule, &ctx.cache_freshness)
+}
+
+fn cache_response(
+    req: &Request,
+    res: &Response,
+    freshness: Freshness,
+    ctx: &ProxyContext,
+) -> Result<()> {
+    let key = req.header.cache_key();
+    let path = mk_file_path(&ctx.cache_dir, &key);
+    let vary = res.header.headers.get("vary").cloned();
+    let ResponseBody::Full(body) = &res.body else {
+        return Err(Error::msg("Streamed responses cannot be cached"));
+    };
+    let status = &res.header.status;
+    let metadata = FileMetadata::new(
+        freshness,
+        (status.code.as_u16(), status.reason.clone()),
+        body.len() as u64,
+        headers::cacheable_headers(&res.header.headers),
+        Some(key.clone()),
+    )?;
+
+    let path = if let Some(vary) = vary {
+        // The file of the URI only records the headers the resource varies on.
+        let stub_headers = headers::Headers::from([("vary".to_string(), vary.clone())]);
+        let stub = FileMetadata::new(
+            freshness,
+            (status.code.as_u16(), status.reason.clone()),
+            0,
+            stub_headers,
+            Some(key),
+        )?;
+        ctx.cache_sender
+            .send(CacheFile::new(stub, path.clone(), Vec::new())?)
+            .context("Failed to queue cache file")?;
+        mk_variant_path(&path, &vary, &req.header.headers)
+    } else {
+        path
+    };
+
+    ctx.cache_sender
+        .send(CacheFile::new(metadata, path, body.clone())?)
+        .context("Failed to queue cache file")
+}
+
+// Pooled connections may have been closed by the service while idle, in which
+// case the request is sent again over a fresh connection. Requests that are not
+// idempotent must not be sent twice, so they always use a fresh connection.
 #[inline(always)]
-fn connect_to_service(service: Service, delay_millis: u64, retries: u16) -> Result<TcpStream> {
-    let host = format!("{}:{}", service.addr, service.port);
-    if let Ok(service_stream) = TcpStream::connect(host.clone()) {
-        return Ok(service_stream);
+async fn send_request(
+    service: &Service,
+    req: &mut Request,
+    ctx: &ProxyContext,
+    deadline: Instant,
+) -> std::result::Result<(ResponseHeader, UpstreamConn), UpstreamError> {
+    let pooled = if req.header.metadata.method.is_idempotent() {
+        ctx.upstream_pool.take(service)
     } else {
-        if retries < 1 {
-            return Err(Error::msg(format!(
-                "Failed to establish connection with service"
-            )));
-        } else {
-            let dur = time::Duration::from_millis(delay_millis);
-            std::thread::sleep(dur);
-            warn!("Connection with server try {}", retries);
-            connect_to_service(service, delay_millis, retries - 1)
+        None
+    };
+    if let Some(mut conn) = pooled {
+        let replied = within_deadline(deadline, async {
+            req.write(&mut conn, service.host()).await.is_ok() && conn.has_response().await
+        })
+        .await?;
+        if replied {
+            let res = within_deadline(deadline, read_final_header(&mut conn))
+                .await?
+                .map_err(UpstreamError::BadResponse)?;
+            return Ok((res, conn));
+        }
+        warn!("Pooled connection to {} was closed", service.host());
+    }
+
+    let service_stream = connect_to_service(service, deadline)
+        .await
+        .map_err(UpstreamError::Connect)?;
+    let mut conn = UpstreamConn::new(service_stream);
+    // A streamed body arrives at the pace of the client, so once it has been sent
+    // the service has the whole failover deadline to reply.
+    let deadline = if req.body.is_streamed() {
+        req.write(&mut conn, service.host()).await.map_err(|err| {
+            if err.downcast_ref::<BodyAborted>().is_some() {
+                UpstreamError::BodyAborted(err)
+            } else {
+                UpstreamError::NoResponse(err)
+            }
+        })?;
+        Instant::now() + time::Duration::from_millis(ctx.failover_deadline)
+    } else {
+        within_deadline(deadline, req.write(&mut conn, service.host()))
+            .await?
+            .map_err(UpstreamError::NoResponse)?;
+        deadline
+    };
+    if !within_deadline(deadline, conn.has_response()).await? {
+        return Err(UpstreamError::NoResponse(Error::msg(
+            "Service closed the connection without replying",
+        )));
+    }
+    let res = within_deadline(deadline, read_final_header(&mut conn))
+        .await?
+        .map_err(UpstreamError::BadResponse)?;
+
+    Ok((res, conn))
+}
+
+// Interim responses such as `100 Continue` are skipped. Of the `1xx` codes only
+// `101 Switching Protocols` is a final response.
+async fn read_final_header(conn: &mut UpstreamConn) -> Result<ResponseHeader> {
+    loop {
+        let res = ResponseHeader::read(conn)
+            .await
+            .context("Failed to parse server response")?;
+        if !matches!(res.status.code, Code::Code100) {
+            return Ok(res);
         }
+        info!("Skipping interim response from service");
     }
 }
+
+// Services that do not reply before the failover deadline are treated as if they
+// had closed the connection.
+async fn within_deadline<F: Future>(
+    deadline: Instant,
+    future: F,
+) -> std::result::Result<F::Output, UpstreamError> {
+    tokio::time::timeout_at(deadline.into(), future)
+        .await
+        .map_err(|_| UpstreamError::NoResponse(Error::msg("Failover deadline exceeded")))
+}
+
+#[inline(always)]
+fn set_connection_headers(res: &mut Response, keep_alive: bool, ctx: &ProxyContext, served: u32) {
+    if keep_alive {
+        res.header
+            .insert_header("connection".to_string(), "keep-alive".to_string());
+        res.header.insert_header(
+            "keep-alive".to_string(),
+            format!(
+                "timeout={}, max={}",
+                ctx.keep_alive_timeout,
+                ctx.keep_alive_max_requests - served
+            ),
+        );
+    } else {
+        res.header
+            .insert_header("connection".to_string(), "close".to_string());
+    }
+}
+
+#[inline(always)]
+async fn connect_to_service(service: &Service, deadline: Instant) -> Result<TcpStream> {
+    let addr = tokio::net::lookup_host(service.host())
+        .await?
+        .next()
+        .context(format!("Failed to resolve {}", service.host()))?;
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Err(Error::msg("Failover deadline exceeded"));
+    }
+
+    tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .context("Failed to establish connection with service")?
+        .context("Failed to establish connection with service")
+}
@@ -1,34 +1,118 @@
 use anyhow::{Context, Error, Result};
 use std::collections::HashMap;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
 
 pub type Headers = HashMap<String, String>;
 
+// Messages whose start line and headers are longer than this are rejected.
+static MAX_HEADER_LEN: usize = 65536;
+
+// Reads the start line and the headers of a message, up to the empty line that
+// ends them. Empty lines before the start line are ignored.
+pub async fn read_header_block<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut header_buff: Vec<u8> = Vec::new();
+
+    loop {
+        let limit = (MAX_HEADER_LEN - header_buff.len()) as u64;
+        let start = header_buff.len();
+        let n = reader
+            .take(limit)
+            .read_until(0x0A, &mut header_buff)
+            .await
+            .context("Error while reading header")?;
+
+        if header_buff.len() >= MAX_HEADER_LEN {
+            return Err(Error::msg("Header too long"));
+        }
+        if n == 0 || header_buff.last() != Some(&0x0A) {
+            return Err(Error::msg("Connection closed before end of header"));
+        }
+
+        let is_empty_line = matches!(&header_buff[start..], b"\r\n" | b"\n");
+        if is_empty_line && start == 0 {
+            header_buff.clear();
+        } else if is_empty_line {
+            break;
+        }
+    }
+
+    Ok(String::from_utf8(header_buff)?)
+}
+
+// Header fields that determine where the body of a message ends.
+static FRAMING_FIELDS: [&str; 2] = ["content-length", "transfer-encoding"];
+
 pub fn parse_headers(input: &str) -> Result<Headers> {
     let mut crlfs = 0;
-    let mut headers = HashMap::new();
+    let mut headers = Headers::new();
     for s in input.split("\r\n") {
-        if s == "" {
+        if s.is_empty() {
             crlfs += 1;
         } else {
             let (key, val) = parse_header(s)?;
-            headers.insert(key, val.to_string());
+            match headers.get_mut(&key) {
+                // Repeated framing fields are merged rather than overwritten, so
+                // conflicting values are noticed instead of silently dropped.
+                Some(prev) if FRAMING_FIELDS.contains(&key.as_str()) => {
+                    prev.push_str(", ");
+                    prev.push_str(val);
+                }
+                _ => {
+                    headers.insert(key, val.to_string());
+                }
+            }
         }
     }
 
     if crlfs != 2 {
-        return Err(Error::msg(format!("Invalid end of headers {}", input)));
+        Err(Error::msg(format!("Invalid end of headers {}", input)))
     } else {
-        return Ok(headers);
+        Ok(headers)
     }
 }
 
-fn parse_header(input: &str) -> Result<(String, &str)> {
+pub fn parse_header(input: &str) -> Result<(String, &str)> {
     let (key, val) = input
         .split_once(':')
         .context(format!("Invalid request-line: {}", input))?;
     Ok((key.to_lowercase(), val.trim()))
 }
 
+pub fn has_connection_token(headers: &Headers, token: &str) -> bool {
+    if let Some(conn) = headers.get("connection") {
+        conn.split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    } else {
+        false
+    }
+}
+
+// Removes the headers that only make sense for a single connection, including
+// the ones listed in the `connection` header itself.
+pub fn remove_hop_by_hop(headers: &mut Headers) {
+    if let Some(conn) = headers.remove("connection") {
+        for token in conn.split(',') {
+            headers.remove(token.trim().to_lowercase().as_str());
+        }
+    }
+
+    for key in hop_by_hop_headers() {
+        headers.remove(key);
+    }
+}
+
+// Headers of a response that are stored in the cache and replayed on hits. The
+// body is stored de-chunked and its length is known, and cookies are never
+// shared between clients.
+pub fn cacheable_headers(headers: &Headers) -> Headers {
+    let mut headers = headers.clone();
+    remove_hop_by_hop(&mut headers);
+    for key in uncacheable_headers() {
+        headers.remove(key);
+    }
+    headers
+}
+
 pub fn parse_version(input: &str) -> Result<&str> {
     if input == "HTTP/1.1" || input == "HTTP-1.1" {
         Ok("HTTP/1.1")
@@ -37,38 +121,25 @@ pub fn parse_version(input: &str) -> Result<&str> {
     }
 }
 
-pub fn is_cacheable_content_type(headers: &Headers) -> bool {
-    if let Some(ct) = headers.get("content-type") {
-        let ct = ct.replace("; charset=UTF-8", "");
-        return cacheable_types().contains(&ct.trim());
-    } else {
-        return false;
-    }
+#[inline(always)]
+fn uncacheable_headers<'a>() -> Vec<&'a str> {
+    vec![
+        "content-length",
+        "transfer-encoding",
+        "set-cookie",
+        "set-cookie2",
+    ]
 }
 
 #[inline(always)]
-fn cacheable_types<'a>() -> Vec<&'a str> {
+fn hop_by_hop_headers<'a>() -> Vec<&'a str> {
     vec![
-        "application/octet-stream",
-        "text/css",
-        "text/javascript",
-        "application/javascript",
-        "image/apng",
-        "image/avif",
-        "image/gif",
-        "image/jpeg",
-        "image/png",
-        "image/svg+xml",
-        "image/webp",
-        "image/bmp",
-        "image/x-icon",
-        "image/tiff",
-        "audio/webm",
-        "audio/mpeg",
-        "audio/ogg",
-        "audio/x-wav",
-        "audio/mp4",
-        "application/ogg",
-        "application/pdf",
+        "keep-alive",
+        "proxy-connection",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "te",
+        "trailer",
+        "upgrade",
     ]
 }
@@ -1,5 +1,13 @@
+pub mod admin;
+pub mod body;
+pub mod cache_control;
+pub mod cache_rules;
+pub mod chunked;
+pub mod coalesce;
 pub mod connection_handler;
 pub mod headers;
+pub mod range;
 pub mod request;
 pub mod response;
 pub mod tcp;
+pub mod upstream;
@@ -0,0 +1,299 @@
+use uuid::Uuid;
+
+use crate::http::headers::Headers;
+use crate::http::response::{Code, Response, ResponseBody};
+
+// Requests asking for more ranges than this get the whole resource instead.
+static MAX_RANGES: usize = 32;
+
+// Whether a `Range` header asks for the whole resource, as clients of media
+// usually do on their first request.
+pub fn is_whole(range: &str) -> bool {
+    range
+        .split_once('=')
+        .is_some_and(|(unit, set)| unit.trim().eq_ignore_ascii_case("bytes") && set.trim() == "0-")
+}
+
+// Answers the `Range` header of a request out of a complete response. Only full
+// `200` responses are cut, anything else is returned as it is, as well as
+// responses to requests whose ranges are invalid or whose `If-Range` does not
+// match.
+pub fn apply(req_headers: &Headers, res: Response) -> Response {
+    let Some(range) = req_headers.get("range") else {
+        return res;
+    };
+    if !matches!(res.header.status.code, Code::Code200)
+        || !if_range_matches(req_headers, &res.header.headers)
+    {
+        return res;
+    }
+    let ResponseBody::Full(body) = &res.body else {
+        // A streamed body can only be sent whole, as long as its length is known.
+        return match &res.body {
+            ResponseBody::Stream(stream) if is_whole(range) && !stream.is_chunked() => {
+                match res.header.get_content_length() {
+                    Some(len) if len > 0 => whole(res, len),
+                    _ => res,
+                }
+            }
+            _ => res,
+        };
+    };
+
+    match parse_ranges(range, body.len() as u64) {
+        None => res,
+        Some(ranges) if ranges.is_empty() => Response::range_not_satisfiable(body.len() as u64),
+        Some(ranges) => partial(res, &ranges),
+    }
+}
+
+// Byte ranges of a `Range` header, as inclusive `(first, last)` positions within
+// a body of `len` bytes. Ranges beyond the end of the body are dropped, so an
+// empty list means none can be satisfied. `None` if the header is invalid and
+// must be ignored.
+fn parse_ranges(range: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, set) = range.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in set
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+
+        if first.is_empty() {
+            // `-<n>` asks for the last `n` bytes.
+            let suffix: u64 = last.parse().ok()?;
+            if suffix > 0 && len > 0 {
+                ranges.push((len.saturating_sub(suffix), len - 1));
+            }
+        } else {
+            let first: u64 = first.parse().ok()?;
+            let last = if last.is_empty() {
+                None
+            } else {
+                Some(last.parse::<u64>().ok()?)
+            };
+            if last.is_some_and(|last| last < first) {
+                return None;
+            }
+            if first < len {
+                ranges.push((first, last.map_or(len - 1, |last| last.min(len - 1))));
+            }
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        None
+    } else {
+        Some(ranges)
+    }
+}
+
+// `If-Range` makes a range request conditional on the response being the one the
+// client already has part of. Only strong validators can be used.
+fn if_range_matches(req_headers: &Headers, res_headers: &Headers) -> bool {
+    let Some(if_range) = req_headers.get("if-range").map(|v| v.trim()) else {
+        return true;
+    };
+
+    if if_range.starts_with('"') {
+        res_headers
+            .get("etag")
+            .is_some_and(|etag| etag.trim() == if_range)
+    } else if if_range.starts_with("W/") {
+        false
+    } else {
+        match (
+            httpdate::parse_http_date(if_range),
+            res_headers
+                .get("last-modified")
+                .map(|date| httpdate::parse_http_date(date)),
+        ) {
+            (Ok(since), Some(Ok(last_modified))) => since == last_modified,
+            _ => false,
+        }
+    }
+}
+
+// Answers a `bytes=0-` range with the whole body of `len` bytes, as it is.
+fn whole(mut res: Response, len: usize) -> Response {
+    res.header.insert_header(
+        "content-range".to_string(),
+        format!("bytes 0-{}/{len}", len - 1),
+    );
+    res.header.status.code = Code::Code206;
+    res.header.status.reason = "Partial Content".to_string();
+    res
+}
+
+// A single range is sent as the body of the response, several ones as the parts
+// of a `multipart/byteranges` body.
+fn partial(mut res: Response, ranges: &[(u64, u64)]) -> Response {
+    let ResponseBody::Full(body) = res.body else {
+        return res;
+    };
+    let len = body.len();
+
+    let body = if let [(first, last)] = ranges {
+        res.header.insert_header(
+            "content-range".to_string(),
+            format!("bytes {first}-{last}/{len}"),
+        );
+        body[*first as usize..=*last as usize].to_vec()
+    } else {
+        let boundary = Uuid::new_v4().simple().to_string();
+        let content_type = res.header.headers.remove("content-type");
+        let mut parts = Vec::new();
+
+        for (first, last) in ranges {
+            parts.extend_from_slice(format!("\r\n--{boundary}\r\n").as_bytes());
+            if let Some(content_type) = &content_type {
+                parts.extend_from_slice(format!("content-type:{content_type}\r\n").as_bytes());
+            }
+            parts.extend_from_slice(
+                format!("content-range:bytes {first}-{last}/{len}\r\n\r\n").as_bytes(),
+            );
+            parts.extend_from_slice(&body[*first as usize..=*last as usize]);
+        }
+        parts.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        res.header.insert_header(
+            "content-type".to_string(),
+            format!("multipart/byteranges; boundary={boundary}"),
+        );
+        parts
+    };
+
+    res.header.status.code = Code::Code206;
+    res.header.status.reason = "Partial Content".to_string();
+    res.header
+        .insert_header("content-length".to_string(), body.len().to_string());
+    res.body = ResponseBody::Full(body);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_headers(fields: &[(&str, &str)]) -> Headers {
+        fields
+            .iter()
+            .map(|(key, val)| (key.to_string(), val.to_string()))
+            .collect()
+    }
+
+    fn mk_response() -> Response {
+        let mut res = Response::plain_text(Code::Code200, "OK", "0123456789".to_string());
+        res.header
+            .insert_header("etag".to_string(), "\"v1\"".to_string());
+        res
+    }
+
+    fn body(res: &Response) -> &[u8] {
+        match &res.body {
+            ResponseBody::Full(body) => body,
+            ResponseBody::Stream(_) => panic!("Unexpected stream"),
+        }
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse_ranges("bytes=-3", 10), Some(vec![(7, 9)]));
+        assert_eq!(parse_ranges("bytes=-20", 10), Some(vec![(0, 9)]));
+        assert_eq!(parse_ranges("bytes=-0", 10), Some(vec![]));
+    }
+
+    #[test]
+    fn last_past_the_end_is_truncated() {
+        assert_eq!(parse_ranges("bytes=5-100", 10), Some(vec![(5, 9)]));
+        assert_eq!(parse_ranges("bytes=5-", 10), Some(vec![(5, 9)]));
+        assert_eq!(parse_ranges("bytes=10-20", 10), Some(vec![]));
+    }
+
+    #[test]
+    fn invalid_ranges_are_ignored() {
+        assert_eq!(parse_ranges("items=0-1", 10), None);
+        assert_eq!(parse_ranges("bytes=5-2", 10), None);
+        assert_eq!(parse_ranges("bytes=a-b", 10), None);
+
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(parse_ranges(&format!("bytes={many}"), 10), None);
+    }
+
+    #[test]
+    fn single_range() {
+        let req_headers = mk_headers(&[("range", "bytes=2-4")]);
+        let res = apply(&req_headers, mk_response());
+
+        assert!(matches!(res.header.status.code, Code::Code206));
+        assert_eq!(body(&res), b"234");
+        assert_eq!(
+            res.header.headers.get("content-range").map(String::as_str),
+            Some("bytes 2-4/10")
+        );
+        assert_eq!(
+            res.header.headers.get("content-length").map(String::as_str),
+            Some("3")
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_range() {
+        let req_headers = mk_headers(&[("range", "bytes=20-30")]);
+        let res = apply(&req_headers, mk_response());
+
+        assert!(matches!(res.header.status.code, Code::Code416));
+        assert_eq!(
+            res.header.headers.get("content-range").map(String::as_str),
+            Some("bytes */10")
+        );
+    }
+
+    #[test]
+    fn multipart_ranges() {
+        let req_headers = mk_headers(&[("range", "bytes=0-1, -2")]);
+        let res = apply(&req_headers, mk_response());
+
+        assert!(matches!(res.header.status.code, Code::Code206));
+        let content_type = res.header.headers.get("content-type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{boundary}\r\ncontent-type:text/plain\r\ncontent-range:bytes 0-1/10\r\n\r\n01\
+             \r\n--{boundary}\r\ncontent-type:text/plain\r\ncontent-range:bytes 8-9/10\r\n\r\n89\
+             \r\n--{boundary}--\r\n"
+        );
+        assert_eq!(body(&res), expected.as_bytes());
+        assert!(!res.header.headers.contains_key("content-range"));
+    }
+
+    #[test]
+    fn if_range_must_match_a_strong_validator() {
+        let res_headers = mk_headers(&[
+            ("etag", "\"v1\""),
+            ("last-modified", "Sun, 18 Oct 2026 06:00:00 GMT"),
+        ]);
+        let matches =
+            |if_range: &str| if_range_matches(&mk_headers(&[("if-range", if_range)]), &res_headers);
+
+        assert!(if_range_matches(&Headers::new(), &res_headers));
+        assert!(matches("\"v1\""));
+        assert!(!matches("\"v2\""));
+        assert!(!matches("W/\"v1\""));
+        assert!(matches("Sun, 18 Oct 2026 06:00:00 GMT"));
+        assert!(!matches("Sun, 18 Oct 2026 05:00:00 GMT"));
+
+        let req_headers = mk_headers(&[("range", "bytes=2-4"), ("if-range", "\"v2\"")]);
+        let res = apply(&req_headers, mk_response());
+        assert!(matches!(res.header.status.code, Code::Code200));
+        assert_eq!(body(&res), b"0123456789");
+    }
+}
@@ -1,11 +1,14 @@
 use anyhow::{Context, Error, Result};
-use log::{info, warn};
+use log::info;
 use mt_logger::{mt_log, Level};
-use std::io::{prelude::*, BufReader, BufWriter};
-use std::net::TcpStream;
+use std::fmt;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufWriter};
 use url::Url;
 
+use crate::http::body::{BodyPump, BodyReader, Framing, RequestBody};
+use crate::http::chunked;
 use crate::http::headers::{self, Headers};
+use crate::http::response::Code;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Method {
@@ -20,6 +23,12 @@ pub enum Method {
 }
 
 impl Method {
+    // Idempotent requests can safely be sent again to another service when the
+    // first one fails without replying.
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, Method::Post | Method::Connect)
+    }
+
     fn to_buffer(&self) -> &[u8] {
         match &self {
             Method::Options => "OPTIONS".as_bytes(),
@@ -34,51 +43,78 @@ impl Method {
     }
 }
 
+// A request the proxy understands but won't serve, which is answered with `code`
+// rather than with a `400`.
+#[derive(Debug)]
+pub struct Rejected {
+    pub code: Code,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code.as_u16(), self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+pub fn content_too_large() -> Error {
+    Error::new(Rejected {
+        code: Code::Code413,
+        reason: "Content Too Large",
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct Request {
     pub header: RequestHeader,
-    pub body: Vec<u8>,
+    pub body: RequestBody,
+    // Set when the framing of the request was suspicious, so that nothing else is
+    // read from its connection.
+    pub must_close: bool,
 }
 
 impl Request {
-    pub fn read(stream: &TcpStream) -> Result<Self> {
-        let (header, body) = split_req(stream)?;
-        Ok(Request { header, body })
+    // Requests whose body is larger than `max_body_size` are rejected with a `413`.
+    pub async fn read<R: AsyncBufRead + Unpin>(reader: &mut R, max_body_size: u64) -> Result<Self> {
+        let (req, _) = split_req(reader, max_body_size, false).await?;
+        Ok(req)
     }
 
-    pub fn write(&mut self, stream: &TcpStream, host: String) {
-        self.header.remove_header("transfer-encoding".to_string());
-        self.header.remove_header("accept-encoding".to_string());
-        self.header.remove_header("content-encoding".to_string());
-        self.header.insert_header("host".to_string(), host);
-
-        let mut writer = BufWriter::new(stream);
-        let data = self.to_buffer();
-        let size = data.len();
-        let buff_size = if size < 2048 { size } else { size / 1024 };
-
-        for chunk in data.chunks(buff_size) {
-            let mut pos = 0;
-            while pos < chunk.len() {
-                if let Ok(bytes_written) = writer.write(&chunk[pos..]) {
-                    pos += bytes_written;
-                    if let Err(_) = writer.flush() {
-                        warn!("Failed to flush request buffer");
-                        return;
-                    }
-                } else {
-                    warn!("Failed to write request");
-                    return;
-                }
-            }
-        }
+    // Requests that are never sent twice have no use for a copy of their body, so
+    // it is streamed to the service by the returned pump as it arrives instead.
+    pub async fn read_streaming<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        max_body_size: u64,
+    ) -> Result<(Self, Option<BodyPump<&mut R>>)> {
+        split_req(reader, max_body_size, true).await
     }
 
-    fn to_buffer(&self) -> Vec<u8> {
-        let mut buffer = self.header.to_buffer();
-        let mut body = self.body.clone();
-        buffer.append(&mut body);
-        buffer
+    // Only the copy of the header sent to the service is changed, so that the
+    // request still carries the client's headers afterwards.
+    pub async fn write<W: AsyncWrite + Unpin>(&self, stream: &mut W, host: String) -> Result<()> {
+        let mut header = self.header.clone();
+        header.remove_header("accept-encoding".to_string());
+        header.remove_header("content-encoding".to_string());
+        // The body is sent right after the header in any case, so the service has
+        // no reason to send `100 Continue`.
+        header.remove_header("expect".to_string());
+        header.insert_header("host".to_string(), host);
+
+        let mut writer = BufWriter::new(stream);
+        writer
+            .write_all(&header.to_buffer())
+            .await
+            .context("Failed to write request")?;
+        self.body
+            .write(&mut writer)
+            .await
+            .context("Failed to write request")?;
+        writer
+            .flush()
+            .await
+            .context("Failed to flush request buffer")
     }
 }
 
@@ -97,6 +133,37 @@ impl RequestHeader {
         }
     }
 
+    pub fn is_keep_alive(&self) -> bool {
+        !headers::has_connection_token(&self.headers, "close")
+    }
+
+    // Identifies the requested resource in the cache. The URI is normalized, so
+    // that equivalent URIs (e.g. with `.` or `..` segments) share the same entry,
+    // and `HEAD` requests share the entry of `GET` ones.
+    pub fn cache_key(&self) -> String {
+        let method = match self.metadata.method {
+            Method::Head => Method::Get,
+            _ => self.metadata.method.clone(),
+        };
+        let method = String::from_utf8_lossy(method.to_buffer());
+        let host = self
+            .headers
+            .get("host")
+            .map(String::as_str)
+            .unwrap_or_default();
+        let uri = self.metadata.uri.as_str();
+        let url = if uri.starts_with('/') {
+            Url::parse(format!("http://{}{}", host, uri).as_str())
+        } else {
+            Url::parse(uri)
+        };
+
+        match url {
+            Ok(url) => format!("{} {}", method, url),
+            Err(_) => format!("{} {}{}", method, host, uri),
+        }
+    }
+
     pub fn insert_header(&mut self, k: String, v: String) {
         self.headers.insert(k, v);
     }
@@ -148,7 +215,7 @@ impl RequestLine {
         let method = self.method.to_buffer();
         let uri = self.uri.as_bytes();
         let version = self.version.as_bytes();
-        let sp = [' ' as u8];
+        let sp = [b' '];
         let crlf = [0x0D, 0x0A];
         let line = [method, &sp, uri, &sp, version, &crlf].concat();
 
@@ -156,55 +223,46 @@ impl RequestLine {
     }
 }
 
-fn split_req(mut stream: &TcpStream) -> Result<(RequestHeader, Vec<u8>)> {
-    let buff = BufReader::new(&mut stream);
-    let mut header_buff: Vec<u8> = Vec::new();
-    let mut body: Vec<u8> = Vec::new();
-    let mut crlfs = 0;
-    let mut it = buff.bytes();
-
-    while crlfs != 2 {
-        match it.next() {
-            Some(Ok(byte)) => {
-                header_buff.push(byte);
-                match byte {
-                    0x0D => {} //do nothing
-                    0x0A => {
-                        crlfs += 1;
-                    }
-                    _ => {
-                        crlfs = 0;
-                    }
-                }
-            }
-            Some(Err(_)) => {
-                return Err(Error::msg(format!("Error while reading request")));
+async fn split_req<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_body_size: u64,
+    stream: bool,
+) -> Result<(Request, Option<BodyPump<&mut R>>)> {
+    let header_str = headers::read_header_block(reader).await?;
+    let mut header = parse_request_header(header_str.as_str())?;
+
+    // Large bodies are spooled to disk rather than kept in memory.
+    let (framing, must_close) = Framing::from_request_headers(&header.headers)?;
+    let mut pump = None;
+    let body = match framing {
+        Some(Framing::Length(len)) if len > max_body_size => return Err(content_too_large()),
+        Some(framing) if stream && !header.metadata.method.is_idempotent() => {
+            if framing == Framing::Chunked {
+                header.remove_header("content-length".to_string());
             }
-            None => {}
+            let (body, body_pump) =
+                RequestBody::stream(BodyReader::new(reader, framing), max_body_size);
+            pump = Some(body_pump);
+            body
         }
-    }
-
-    let header_str = String::from_utf8(header_buff)?;
-    let header = parse_request_header(header_str.as_str())?;
-
-    if let Some(len) = header.get_content_length() {
-        let mut read_bytes = 0;
-
-        while read_bytes < len {
-            match it.next() {
-                Some(Ok(byte)) => {
-                    body.push(byte);
-                    read_bytes += 1;
-                }
-                Some(Err(_)) => {
-                    return Err(Error::msg(format!("Error while reading request")));
-                }
-                None => {}
+        Some(framing) => {
+            let mut body_reader = BodyReader::new(&mut *reader, framing);
+            let (body, len) = RequestBody::read(&mut body_reader, max_body_size).await?;
+            if framing == Framing::Chunked {
+                let trailers = body_reader.trailers().clone();
+                chunked::dechunk_headers(&mut header.headers, len as usize, trailers);
             }
+            body
         }
-    }
+        None => RequestBody::Memory(Vec::new()),
+    };
 
-    return Ok((header, body));
+    let req = Request {
+        header,
+        body,
+        must_close,
+    };
+    Ok((req, pump))
 }
 
 pub fn parse_request_header(input: &str) -> Result<RequestHeader> {
@@ -232,7 +290,7 @@ fn parse_request_line(input: &str) -> Result<RequestLine> {
     let version = headers::parse_version(s)?;
 
     Ok(RequestLine {
-        method: method,
+        method,
         uri: uri.to_string(),
         version: version.to_string(),
     })
@@ -252,16 +310,83 @@ fn parse_method(input: &str) -> Result<Method> {
     }
 }
 
-fn parse_uri<'a>(input: &'a str) -> Result<&str> {
+fn parse_uri(input: &str) -> Result<&str> {
     let prefix = if input.starts_with("/") {
         "http://host"
     } else {
         ""
     };
 
-    if let Ok(_) = Url::parse(format!("{}{}", prefix, input).as_str()) {
+    if Url::parse(format!("{}{}", prefix, input).as_str()).is_ok() {
         Ok(input)
     } else {
         Err(Error::msg(format!("Invalid request-uri: {:?}", input)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn read(input: &str, max_body_size: u64) -> Result<Request> {
+        block_on(Request::read(&mut input.as_bytes(), max_body_size))
+    }
+
+    fn rejected_code(err: Error) -> Option<u16> {
+        err.downcast_ref::<Rejected>().map(|r| r.code.as_u16())
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_rejected() {
+        let req = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(read(req, 5).is_ok());
+        assert_eq!(read(req, 4).err().and_then(rejected_code), Some(413));
+
+        let req =
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n";
+        let body = read(req, 5).unwrap().body;
+        assert!(matches!(body, RequestBody::Memory(data) if data == b"hello"));
+        assert_eq!(read(req, 4).err().and_then(rejected_code), Some(413));
+    }
+
+    fn header(method: Method, uri: &str) -> RequestHeader {
+        let mut headers = Headers::new();
+        headers.insert("host".to_string(), "example.com".to_string());
+        RequestHeader {
+            metadata: RequestLine {
+                method,
+                uri: uri.to_string(),
+                version: "HTTP/1.1".to_string(),
+            },
+            headers,
+        }
+    }
+
+    #[test]
+    fn dot_segments_are_resolved_in_cache_keys() {
+        let key = header(Method::Get, "/img.png").cache_key();
+        assert_eq!(key, "GET http://example.com/img.png");
+        assert_eq!(header(Method::Get, "/a/../img.png").cache_key(), key);
+        assert_eq!(header(Method::Get, "/../../img.png").cache_key(), key);
+    }
+
+    #[test]
+    fn head_requests_share_the_get_cache_key() {
+        assert_eq!(
+            header(Method::Head, "/img.png").cache_key(),
+            header(Method::Get, "/img.png").cache_key()
+        );
+        assert_ne!(
+            header(Method::Post, "/img.png").cache_key(),
+            header(Method::Get, "/img.png").cache_key()
+        );
+    }
+}
@@ -1,12 +1,17 @@
 use anyhow::{Context, Error, Result};
-use log::{info, warn};
+use log::info;
 use mt_logger::{mt_log, Level};
 use std::collections::HashMap;
-use std::io::{prelude::*, BufReader, BufWriter};
-use std::net::TcpStream;
-
-use crate::cache::io::CacheFile;
+use std::fmt;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufWriter};
+
+use crate::cache::io::{CacheFile, FileMetadata};
+use crate::http::body::{BodyReader, BodyStream, Framing};
+use crate::http::cache_control::{self, Freshness};
+use crate::http::cache_rules::CacheRule;
+use crate::http::chunked;
 use crate::http::headers::{self, Headers};
+use crate::http::request::Method;
 
 #[derive(Debug, Clone)]
 pub enum Code {
@@ -57,6 +62,16 @@ pub enum Code {
 }
 
 impl Code {
+    pub fn as_u16(&self) -> u16 {
+        String::from_utf8_lossy(self.to_buffer())
+            .parse()
+            .unwrap_or(0)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        self.as_u16() >= 500
+    }
+
     fn to_buffer(&self) -> &[u8] {
         match &self {
             Code::Code100 => "100".as_bytes(),
@@ -107,33 +122,96 @@ impl Code {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Response {
     pub header: ResponseHeader,
-    pub body: Vec<u8>,
+    pub body: ResponseBody,
+}
+
+pub enum ResponseBody {
+    Full(Vec<u8>),
+    // Piped from the service to the client as it arrives.
+    Stream(Box<BodyStream>),
+}
+
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseBody::Full(body) => f.debug_tuple("Full").field(&body.len()).finish(),
+            ResponseBody::Stream(_) => f.write_str("Stream"),
+        }
+    }
 }
 
-static MAX_CACHE_SIZE_MB: u32 = 30;
 impl Response {
+    // Replays the stored response, with an `Age` header telling how old it is.
     pub fn from_cache_file(file: CacheFile) -> Self {
         let status = StatusLine {
             version: "HTTP/1.1".to_string(),
-            code: Code::Code200,
-            reason: "OK".to_string(),
+            code: parse_code(&file.metadata.status.to_string()).unwrap_or(Code::Code200),
+            reason: file.metadata.reason.clone(),
         };
         let mut header = ResponseHeader::new(status);
 
-        if let Some(content_type) = file.metadata.content_type {
-            header.insert_header("content-type".to_string(), content_type);
-        }
+        let age = file.metadata.age();
+        header.headers.extend(file.metadata.headers);
         header.insert_header(
             "content-length".to_string(),
             file.metadata.content_length.to_string(),
         );
+        header.insert_header("age".to_string(), age.to_string());
+        // Ranges of stored responses are cut by the proxy itself.
+        header.insert_header("accept-ranges".to_string(), "bytes".to_string());
 
         Response {
             header,
-            body: file.content_data,
+            body: ResponseBody::Full(file.content_data),
+        }
+    }
+
+    pub fn not_modified(metadata: &FileMetadata) -> Self {
+        let status = StatusLine {
+            version: "HTTP/1.1".to_string(),
+            code: Code::Code304,
+            reason: "Not Modified".to_string(),
+        };
+        let mut header = ResponseHeader::new(status);
+        header.remove_header("content-length".to_string());
+
+        if let Some(etag) = &metadata.etag {
+            header.insert_header("etag".to_string(), etag.clone());
+        }
+        if let Some(last_modified) = &metadata.last_modified {
+            header.insert_header("last-modified".to_string(), last_modified.clone());
+        }
+        // The client must update its copy with the caching headers of the resource.
+        for key in ["cache-control", "expires", "vary"] {
+            if let Some(value) = metadata.headers.get(key) {
+                header.insert_header(key.to_string(), value.clone());
+            }
+        }
+
+        Response {
+            header,
+            body: ResponseBody::Full(Vec::new()),
+        }
+    }
+
+    pub fn range_not_satisfiable(content_length: u64) -> Self {
+        let status = StatusLine {
+            version: "HTTP/1.1".to_string(),
+            code: Code::Code416,
+            reason: "Range Not Satisfiable".to_string(),
+        };
+        let mut header = ResponseHeader::new(status);
+        header.insert_header(
+            "content-range".to_string(),
+            format!("bytes */{content_length}"),
+        );
+
+        Response {
+            header,
+            body: ResponseBody::Full(Vec::new()),
         }
     }
 
@@ -147,7 +225,7 @@ impl Response {
 
         Response {
             header,
-            body: Vec::new(),
+            body: ResponseBody::Full(Vec::new()),
         }
     }
 
@@ -161,74 +239,110 @@ impl Response {
 
         Response {
             header,
-            body: Vec::new(),
+            body: ResponseBody::Full(Vec::new()),
         }
     }
 
-    pub fn get_content_type(&self) -> Option<String> {
-        self.header
-            .headers
-            .get(&"content-type".to_string())
-            .map(|s| s.clone())
+    pub fn plain_text(code: Code, reason: &str, body: String) -> Self {
+        let status = StatusLine {
+            version: "HTTP/1.1".to_string(),
+            code,
+            reason: reason.to_string(),
+        };
+        let mut header = ResponseHeader::new(status);
+        header.insert_header("content-type".to_string(), "text/plain".to_string());
+        header.insert_header("content-length".to_string(), body.len().to_string());
+
+        Response {
+            header,
+            body: ResponseBody::Full(body.into_bytes()),
+        }
     }
 
-    pub fn read(stream: &TcpStream) -> Result<Self> {
-        let (header, body) = split_res(stream)?;
-        Ok(Response { header, body })
+    // Streamed bodies can only be read once, so they cannot be copied.
+    pub fn try_clone(&self) -> Option<Response> {
+        match &self.body {
+            ResponseBody::Full(body) => Some(Response {
+                header: self.header.clone(),
+                body: ResponseBody::Full(body.clone()),
+            }),
+            ResponseBody::Stream(_) => None,
+        }
     }
 
-    pub fn write(&mut self, stream: &TcpStream) {
+    // Reads the whole response, for callers that need its body in memory.
+    pub async fn read<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        req_method: &Method,
+    ) -> Result<Self> {
+        let mut header = ResponseHeader::read(reader).await?;
+        let mut body = Vec::new();
+
+        if header.has_body(req_method) {
+            let framing = Framing::from_headers(&header.headers).unwrap_or(Framing::UntilClose);
+            let mut body_reader = BodyReader::new(&mut *reader, framing);
+            body_reader
+                .read_to_end(&mut body, u64::MAX)
+                .await
+                .context("Error while reading response")?;
+
+            body_reader.set_length(&mut header.headers, body.len());
+        }
+
+        Ok(Response {
+            header,
+            body: ResponseBody::Full(body),
+        })
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(mut self, stream: &mut W) -> Result<()> {
         self.header
             .insert_header("server".to_string(), "rusty-proxy".to_string());
 
         let mut writer = BufWriter::new(stream);
-        let data = self.to_buffer();
-        let size = data.len();
-        let buff_size = if size < 2048 { size } else { size / 1024 };
-
-        for chunk in data.chunks(buff_size) {
-            let mut pos = 0;
-            while pos < chunk.len() {
-                if let Ok(bytes_written) = writer.write(&chunk[pos..]) {
-                    pos += bytes_written;
-                    if let Err(_) = writer.flush() {
-                        warn!("Failed to flush response buffer");
-                        return;
-                    }
+        match self.body {
+            ResponseBody::Full(body) => {
+                writer.write_all(&self.header.to_buffer()).await?;
+                // Complete bodies are de-chunked when they are read, so the header
+                // is only left on responses without a body, such as those to `HEAD`
+                // requests. Their last chunk would be read as the next response.
+                if chunked::is_chunked(&self.header.headers) && !body.is_empty() {
+                    writer
+                        .write_all(&chunked::encode(
+                            &body,
+                            chunked::CHUNK_SIZE,
+                            &Headers::new(),
+                        ))
+                        .await?;
                 } else {
-                    warn!("Failed to write response");
-                    return;
+                    writer.write_all(&body).await?;
+                }
+            }
+            ResponseBody::Stream(body) => {
+                if body.is_chunked() {
+                    self.header.remove_header("content-length".to_string());
+                    if !chunked::is_chunked(&self.header.headers) {
+                        self.header
+                            .insert_header("transfer-encoding".to_string(), "chunked".to_string());
+                    }
                 }
+                writer.write_all(&self.header.to_buffer()).await?;
+                body.pipe(&mut writer)
+                    .await
+                    .context("Failed to stream response body")?;
             }
         }
-    }
-
-    pub fn is_cacheable(&self) -> bool {
-        let is_valid_status_code = match self.header.status.code {
-            Code::Code200
-            | Code::Code201
-            | Code::Code202
-            | Code::Code203
-            | Code::Code204
-            | Code::Code205
-            | Code::Code206 => true,
-            _ => false,
-        };
-
-        self.body_size_mb() <= MAX_CACHE_SIZE_MB
-            && is_valid_status_code
-            && headers::is_cacheable_content_type(&self.header.headers)
-    }
 
-    pub fn body_size_mb(&self) -> u32 {
-        (self.body.len() / 1048576) as u32
+        writer.flush().await.context("Failed to write response")
     }
 
-    fn to_buffer(&self) -> Vec<u8> {
-        let mut buffer = self.header.to_buffer();
-        let mut body = self.body.clone();
-        buffer.append(&mut body);
-        buffer
+    // Only complete bodies can be stored, and only if they fit in the rule's size limit.
+    pub fn cache_freshness(&self, rule: &CacheRule, defaults: &Freshness) -> Option<Freshness> {
+        if rule.allows(self) {
+            self.header.cache_freshness(rule, defaults)
+        } else {
+            None
+        }
     }
 }
 
@@ -242,10 +356,40 @@ impl ResponseHeader {
     pub fn new(status: StatusLine) -> Self {
         let mut headers = HashMap::new();
         headers.insert("server".to_string(), "rusty-proxy".to_string());
+        headers.insert("content-length".to_string(), "0".to_string());
 
         ResponseHeader { status, headers }
     }
 
+    pub async fn read<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let header_str = headers::read_header_block(reader).await?;
+        parse_response_header(header_str.as_str())
+    }
+
+    pub fn get_content_type(&self) -> Option<String> {
+        self.headers.get("content-type").cloned()
+    }
+
+    // How long the response can be served from the cache, honoring the caching
+    // directives of the service. `None` if it must not be cached. Responses that
+    // are stale right away are only worth caching if they can be revalidated.
+    pub fn cache_freshness(&self, rule: &CacheRule, defaults: &Freshness) -> Option<Freshness> {
+        // `Vary: *` means the response depends on more than the request headers.
+        let varies_on_anything = self
+            .headers
+            .get("vary")
+            .is_some_and(|vary| vary.split(',').any(|name| name.trim() == "*"));
+
+        if rule.allows_header(self) && !varies_on_anything {
+            let has_validators =
+                self.headers.contains_key("etag") || self.headers.contains_key("last-modified");
+            cache_control::freshness(&self.headers, &rule.freshness_defaults(defaults))
+                .filter(|freshness| freshness.ttl > 0 || has_validators)
+        } else {
+            None
+        }
+    }
+
     pub fn get_content_length(&self) -> Option<usize> {
         if let Some(h) = self.headers.get("content-length") {
             h.parse().ok()
@@ -254,6 +398,19 @@ impl ResponseHeader {
         }
     }
 
+    pub fn is_keep_alive(&self) -> bool {
+        !headers::has_connection_token(&self.headers, "close")
+    }
+
+    pub fn has_body(&self, req_method: &Method) -> bool {
+        let no_body_status = matches!(
+            self.status.code,
+            Code::Code100 | Code::Code101 | Code::Code204 | Code::Code304
+        );
+
+        *req_method != Method::Head && !no_body_status
+    }
+
     pub fn insert_header(&mut self, k: String, v: String) {
         self.headers.insert(k, v);
     }
@@ -305,7 +462,7 @@ impl StatusLine {
         let version = self.version.as_bytes();
         let code = self.code.to_buffer();
         let reason = self.reason.as_bytes();
-        let sp = [' ' as u8];
+        let sp = [b' '];
         let crlf = [0x0D, 0x0A];
         let line = [version, &sp, code, &sp, reason, &crlf].concat();
 
@@ -313,57 +470,6 @@ impl StatusLine {
     }
 }
 
-fn split_res(mut stream: &TcpStream) -> Result<(ResponseHeader, Vec<u8>)> {
-    let buff = BufReader::new(&mut stream);
-    let mut header_buff: Vec<u8> = Vec::new();
-    let mut body: Vec<u8> = Vec::new();
-    let mut crlfs = 0;
-    let mut it = buff.bytes();
-
-    while crlfs != 2 {
-        match it.next() {
-            Some(Ok(byte)) => {
-                header_buff.push(byte);
-                match byte {
-                    0x0D => {} //do nothing
-                    0x0A => {
-                        crlfs += 1;
-                    }
-                    _ => {
-                        crlfs = 0;
-                    }
-                }
-            }
-            Some(Err(_)) => {
-                return Err(Error::msg(format!("Error while reading response")));
-            }
-            None => {}
-        }
-    }
-
-    let header_str = String::from_utf8(header_buff)?;
-    let header = parse_response_header(header_str.as_str())?;
-
-    if let Some(len) = header.get_content_length() {
-        let mut read_bytes = 0;
-
-        while read_bytes < len {
-            match it.next() {
-                Some(Ok(byte)) => {
-                    body.push(byte);
-                    read_bytes += 1;
-                }
-                Some(Err(_)) => {
-                    return Err(Error::msg(format!("Error while reading request")));
-                }
-                None => {}
-            }
-        }
-    }
-
-    return Ok((header, body));
-}
-
 pub fn parse_response_header(input: &str) -> Result<ResponseHeader> {
     let (s, rest) = input
         .split_once("\r\n")
@@ -390,7 +496,7 @@ fn parse_status_line(input: &str) -> Result<StatusLine> {
 
     Ok(StatusLine {
         version: version.to_string(),
-        code: code,
+        code,
         reason: reason.to_string(),
     })
 }
@@ -431,13 +537,13 @@ fn parse_code(input: &str) -> Result<Code> {
         "415" => Ok(Code::Code415),
         "416" => Ok(Code::Code416),
         "417" => Ok(Code::Code417),
-        "500" => Ok(Code::Code417),
-        "501" => Ok(Code::Code417),
-        "502" => Ok(Code::Code417),
-        "503" => Ok(Code::Code417),
-        "504" => Ok(Code::Code417),
-        "505" => Ok(Code::Code417),
-        fail_code => match fail_code.chars().nth(0).unwrap() {
+        "500" => Ok(Code::Code500),
+        "501" => Ok(Code::Code501),
+        "502" => Ok(Code::Code502),
+        "503" => Ok(Code::Code503),
+        "504" => Ok(Code::Code504),
+        "505" => Ok(Code::Code505),
+        fail_code => match fail_code.chars().next().unwrap() {
             '1' => Ok(Code::Code100),
             '2' => Ok(Code::Code200),
             '3' => Ok(Code::Code300),
@@ -447,3 +553,48 @@ fn parse_code(input: &str) -> Result<Code> {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn write(res: Response) -> Vec<u8> {
+        let mut out = Vec::new();
+        block_on(res.write(&mut out)).unwrap();
+        out
+    }
+
+    fn body_of(out: &[u8]) -> &[u8] {
+        let end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        &out[end + 4..]
+    }
+
+    #[test]
+    fn responses_without_body_are_not_chunk_encoded() {
+        let header =
+            parse_response_header("HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n").unwrap();
+        let res = Response {
+            header,
+            body: ResponseBody::Full(Vec::new()),
+        };
+
+        assert_eq!(body_of(&write(res)), b"");
+    }
+
+    #[test]
+    fn full_bodies_are_written_as_they_are() {
+        let res = Response::plain_text(Code::Code200, "OK", "hello".to_string());
+        let out = write(res);
+
+        assert_eq!(body_of(&out), b"hello");
+        assert!(String::from_utf8_lossy(&out).contains("content-length:5\r\n"));
+    }
+}
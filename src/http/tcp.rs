@@ -1,48 +1,24 @@
 use anyhow::{Context, Result};
 use log::warn;
-use std::net::TcpListener;
-use std::sync::mpsc::Sender;
+use tokio::net::TcpListener;
 
-use crate::cache::io::CacheFile;
-use crate::concurrent::ccfifo_queue::CCFifoQueue;
-use crate::concurrent::pool::ThreadPool;
-use crate::http::connection_handler::http_handler;
-use crate::opts::Service;
-use std::path::PathBuf;
+use crate::http::connection_handler::{http_handler, ProxyContext};
 
-pub fn mk_tcp_listener(addr: String, port: u16) -> Result<TcpListener> {
+pub async fn mk_tcp_listener(addr: String, port: u16) -> Result<TcpListener> {
     let addr = format!("{}:{:?}", addr, port);
-    return TcpListener::bind(addr.clone())
-        .context(format!("Failed to bind TcpListener to {}", addr));
+    TcpListener::bind(addr.clone())
+        .await
+        .context(format!("Failed to bind TcpListener to {}", addr))
 }
 
-pub fn listen_connections(
-    listener: &TcpListener,
-    pool: &ThreadPool,
-    cache_dir: PathBuf,
-    cache_ttl: u64,
-    cache_sender: &Sender<CacheFile>,
-    addr_queue: &CCFifoQueue<Service>,
-    failure_delay: u64,
-    failure_retries: u16,
-) {
-    for conn in listener.incoming() {
-        match conn {
-            Ok(stream) => {
-                let addr_q = addr_queue.clone();
-                let sender = cache_sender.clone();
-                let cache = cache_dir.clone();
-                pool.execute(move || {
-                    http_handler(
-                        stream,
-                        cache,
-                        cache_ttl,
-                        sender,
-                        addr_q,
-                        failure_delay,
-                        failure_retries,
-                    )
-                });
+// Each connection is served by its own task, so idle and slow clients only cost
+// the memory of their task rather than a thread.
+pub async fn listen_connections(listener: TcpListener, ctx: ProxyContext) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let ctx = ctx.clone();
+                tokio::spawn(http_handler(stream, ctx));
             }
             Err(err) => warn!("{:?}", err),
         }
@@ -0,0 +1,137 @@
+use log::{error, info};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::opts::Service;
+
+pub struct UpstreamConn {
+    stream: BufReader<TcpStream>,
+    idle_since: Instant,
+}
+
+impl UpstreamConn {
+    pub fn new(stream: TcpStream) -> Self {
+        UpstreamConn {
+            stream: BufReader::new(stream),
+            idle_since: Instant::now(),
+        }
+    }
+
+    // Waits until the server starts answering or closes the connection.
+    pub async fn has_response(&mut self) -> bool {
+        matches!(self.stream.fill_buf().await, Ok(buff) if !buff.is_empty())
+    }
+
+    // A pooled connection is only reusable if the server has neither closed it
+    // nor sent anything since the last response was read.
+    fn is_alive(&self) -> bool {
+        if !self.stream.buffer().is_empty() {
+            return false;
+        }
+
+        // Whatever is read here makes the connection unusable anyway.
+        let mut buff = [0u8; 1];
+        match self.stream.get_ref().try_read(&mut buff) {
+            Ok(_) => false,
+            Err(err) => err.kind() == ErrorKind::WouldBlock,
+        }
+    }
+}
+
+impl AsyncRead for UpstreamConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for UpstreamConn {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().stream).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.stream).consume(amt)
+    }
+}
+
+impl AsyncWrite for UpstreamConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[derive(Clone)]
+pub struct UpstreamPool {
+    idle: Arc<Mutex<HashMap<String, Vec<UpstreamConn>>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl UpstreamPool {
+    pub fn new(max_idle: usize, idle_timeout_secs: u64) -> Self {
+        UpstreamPool {
+            idle: Arc::new(Mutex::new(HashMap::new())),
+            max_idle,
+            idle_timeout: Duration::from_secs(idle_timeout_secs),
+        }
+    }
+
+    pub fn take(&self, service: &Service) -> Option<UpstreamConn> {
+        if let Ok(mut idle) = self.idle.lock() {
+            let conns = idle.get_mut(&service.host())?;
+
+            // Most recently used connections are the least likely to have been closed by the server.
+            while let Some(conn) = conns.pop() {
+                if conn.idle_since.elapsed() < self.idle_timeout && conn.is_alive() {
+                    info!("Reusing connection to {}", service.host());
+                    return Some(conn);
+                }
+            }
+
+            None
+        } else {
+            error!("UpstreamPool: Failed to get lock");
+            None
+        }
+    }
+
+    pub fn put(&self, service: &Service, mut conn: UpstreamConn) {
+        if self.max_idle == 0 {
+            return;
+        }
+
+        if let Ok(mut idle) = self.idle.lock() {
+            let conns = idle.entry(service.host()).or_default();
+            conns.retain(|c| c.idle_since.elapsed() < self.idle_timeout);
+
+            if conns.len() < self.max_idle {
+                conn.idle_since = Instant::now();
+                conns.push(conn);
+            }
+        } else {
+            error!("UpstreamPool: Failed to get lock");
+        }
+    }
+}
@@ -1,4 +1,4 @@
+pub mod balancer;
 pub mod cache;
-pub mod concurrent;
 pub mod http;
 pub mod opts;
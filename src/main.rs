@@ -2,13 +2,21 @@ use std::path::Path;
 
 use mt_logger::{mt_new, Level, OutputStream};
 use std::process::exit;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
+use tokio::runtime;
 
+use rusty_proxy::balancer::health::{HealthChecker, ServiceHealth};
+use rusty_proxy::balancer::mk_balancer;
 use rusty_proxy::cache::cleaner::CacheCleaner;
+use rusty_proxy::cache::memory::MemoryCache;
 use rusty_proxy::cache::writer::CacheWriter;
-use rusty_proxy::concurrent::ccfifo_queue::CCFifoQueue;
-use rusty_proxy::concurrent::pool::ThreadPool;
+use rusty_proxy::http::admin::AdminServer;
+use rusty_proxy::http::cache_control::Freshness;
+use rusty_proxy::http::cache_rules::CacheRules;
+use rusty_proxy::http::coalesce::Coalescer;
+use rusty_proxy::http::connection_handler::ProxyContext;
 use rusty_proxy::http::tcp::{listen_connections, mk_tcp_listener};
+use rusty_proxy::http::upstream::UpstreamPool;
 use rusty_proxy::opts::read_opts_file;
 
 fn main() {
@@ -30,28 +38,136 @@ fn main() {
                 exit(1);
             }
 
+            if opts.cache_max_size_mb == Some(0) {
+                println!("Property 'cache_max_size_mb' must be > 0");
+                exit(1);
+            }
+
+            if opts.keep_alive_timeout_secs < 1 {
+                println!("Property 'keep_alive_timeout_secs' must be > 0");
+                exit(1);
+            }
+
+            if opts.keep_alive_max_requests < 1 {
+                println!("Property 'keep_alive_max_requests' must be > 0");
+                exit(1);
+            }
+
+            if opts.max_request_body_size_mb < 1 {
+                println!("Property 'max_request_body_size_mb' must be > 0");
+                exit(1);
+            }
+
+            if opts.services.iter().any(|s| s.weight() < 1) {
+                println!("Property 'weight' of services must be > 0");
+                exit(1);
+            }
+
+            if let Some(health_check) = &opts.health_check {
+                if health_check.interval_secs < 1
+                    || health_check.timeout_ms < 1
+                    || health_check.rise < 1
+                    || health_check.fall < 1
+                {
+                    println!("Properties 'interval_secs', 'timeout_ms', 'rise' and 'fall' of 'health_check' must be > 0");
+                    exit(1);
+                }
+            }
+
+            if let Some(circuit_breaker) = &opts.circuit_breaker {
+                if circuit_breaker.failure_threshold < 1 {
+                    println!("Property 'failure_threshold' of 'circuit_breaker' must be > 0");
+                    exit(1);
+                }
+            }
+
+            let cache_rules = match CacheRules::new(&opts.cache.rules) {
+                Ok(cache_rules) => cache_rules,
+                Err(err) => {
+                    println!("Property 'cache' is invalid: {err:#}");
+                    exit(1);
+                }
+            };
+
             let cache_dir = Path::new(opts.cache_dir.as_str());
             let cache_ttl_secs = (opts.cache_ttl_mins * 60) as u64;
-            let pool = ThreadPool::new(opts.workers as usize);
-            let addr_queue = CCFifoQueue::new(opts.services);
             let (cache_sender, cache_receiver) = mpsc::channel();
 
-            println!("Listening on {}:{}", opts.addr, opts.port);
-            let listener = mk_tcp_listener(opts.addr, opts.port).unwrap();
+            // Connections are served by tasks spread over `workers` threads.
+            let runtime = match runtime::Builder::new_multi_thread()
+                .worker_threads(opts.workers as usize)
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(err) => {
+                    println!("Failed to start runtime: {err}");
+                    exit(1);
+                }
+            };
+            let _guard = runtime.enter();
 
-            CacheWriter::run(cache_receiver);
-            CacheCleaner::run(cache_dir.clone().to_path_buf());
+            println!("Listening on {}:{}", opts.addr, opts.port);
+            let listener = runtime
+                .block_on(mk_tcp_listener(opts.addr, opts.port))
+                .unwrap();
 
-            listen_connections(
-                &listener,
-                &pool,
+            let memory_cache = MemoryCache::new(opts.cache_memory_size_mb * 1024 * 1024);
+            CacheWriter::run(cache_receiver, memory_cache.clone());
+            CacheCleaner::run(
                 cache_dir.to_path_buf(),
-                cache_ttl_secs,
-                &cache_sender,
-                &addr_queue,
-                opts.failure_delay,
-                opts.failure_retries,
+                opts.cache_max_size_mb.map(|mb| mb * 1024 * 1024),
+                memory_cache.clone(),
             );
+
+            if let Some(admin) = &opts.admin {
+                println!("Admin API listening on {}:{}", admin.addr, admin.port);
+                let admin_listener = runtime
+                    .block_on(mk_tcp_listener(admin.addr.clone(), admin.port))
+                    .unwrap();
+                AdminServer::run(
+                    admin_listener,
+                    cache_dir.to_path_buf(),
+                    memory_cache.clone(),
+                );
+            }
+
+            let health = ServiceHealth::new(opts.circuit_breaker.clone());
+            if let Some(health_check) = opts.health_check {
+                HealthChecker::run(opts.services.clone(), health.clone(), health_check);
+            }
+
+            let ctx = ProxyContext {
+                cache_dir: cache_dir.to_path_buf(),
+                cache_freshness: Freshness {
+                    ttl: cache_ttl_secs,
+                    stale_while_revalidate: opts.cache_stale_while_revalidate_secs,
+                    stale_if_error: opts.cache_stale_if_error_secs,
+                },
+                cache_rules: Arc::new(cache_rules),
+                cache_sender,
+                memory_cache,
+                coalescer: Coalescer::new(),
+                balancer: mk_balancer(
+                    &opts.strategy,
+                    &opts.hash_key,
+                    opts.services,
+                    health.clone(),
+                ),
+                failure_delay: opts.failure_delay,
+                failure_retries: opts.failure_retries,
+                failover_deadline: opts.failover_deadline_ms,
+                health,
+                keep_alive_timeout: opts.keep_alive_timeout_secs,
+                keep_alive_max_requests: opts.keep_alive_max_requests,
+                max_request_body_size: opts.max_request_body_size_mb * 1024 * 1024,
+                upstream_pool: UpstreamPool::new(
+                    opts.upstream_max_idle,
+                    opts.upstream_idle_timeout_secs,
+                ),
+            };
+
+            runtime.block_on(listen_connections(listener, ctx));
         }
         None => println!(
             "Path to process file not provided. Usage: `rusty_proxy /path/to/process.yaml`"
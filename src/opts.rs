@@ -8,16 +8,142 @@ pub struct Opts {
     pub addr: String,
     pub cache_dir: String,
     pub cache_ttl_mins: u16,
+    pub cache_max_size_mb: Option<u64>,
+    #[serde(default = "default_cache_memory_size_mb")]
+    pub cache_memory_size_mb: u64,
+    #[serde(default)]
+    pub cache_stale_while_revalidate_secs: u64,
+    #[serde(default)]
+    pub cache_stale_if_error_secs: u64,
+    #[serde(default)]
+    pub cache: Cache,
     pub workers: u16,
     pub failure_delay: u64,
     pub failure_retries: u16,
+    #[serde(default = "default_failover_deadline_ms")]
+    pub failover_deadline_ms: u64,
+    #[serde(default = "default_keep_alive_timeout_secs")]
+    pub keep_alive_timeout_secs: u64,
+    #[serde(default = "default_keep_alive_max_requests")]
+    pub keep_alive_max_requests: u32,
+    #[serde(default = "default_max_request_body_size_mb")]
+    pub max_request_body_size_mb: u64,
+    #[serde(default = "default_upstream_max_idle")]
+    pub upstream_max_idle: usize,
+    #[serde(default = "default_upstream_idle_timeout_secs")]
+    pub upstream_idle_timeout_secs: u64,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub hash_key: HashKey,
+    pub health_check: Option<HealthCheck>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub admin: Option<Admin>,
     pub services: Vec<Service>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct Cache {
+    #[serde(default = "default_cache_rules")]
+    pub rules: Vec<CacheRule>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            rules: default_cache_rules(),
+        }
+    }
+}
+
+// Matches requests by path regex and method, and responses by status code, content
+// type (e.g. `image/png` or `image/*`) and size.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheRule {
+    #[serde(default = "default_cache_rule_path")]
+    pub path: String,
+    #[serde(default = "default_cache_rule_content_types")]
+    pub content_types: Vec<String>,
+    #[serde(default = "default_cache_rule_methods")]
+    pub methods: Vec<String>,
+    #[serde(default = "default_cache_rule_status_codes")]
+    pub status_codes: Vec<u16>,
+    pub ttl_secs: Option<u64>,
+    #[serde(default = "default_cache_rule_max_size_mb")]
+    pub max_size_mb: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    ConsistentHash,
+}
+
+// Part of the request hashed by the `consistent_hash` strategy. Requests lacking
+// the cookie or header are hashed by client IP.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum HashKey {
+    #[default]
+    ClientIp,
+    Cookie {
+        name: String,
+    },
+    Header {
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheck {
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_status")]
+    pub expected_status: u16,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_health_check_rise")]
+    pub rise: u32,
+    #[serde(default = "default_health_check_fall")]
+    pub fall: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreaker {
+    #[serde(default = "default_circuit_breaker_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+// Listener of the admin API, which should not be reachable by clients.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Admin {
+    #[serde(default = "default_admin_addr")]
+    pub addr: String,
+    pub port: u16,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Service {
     pub addr: String,
     pub port: u16,
+    pub weight: Option<u32>,
+}
+
+impl Service {
+    pub fn host(&self) -> String {
+        format!("{}:{}", self.addr, self.port)
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
 }
 
 pub fn read_opts_file(path: &str) -> Opts {
@@ -32,3 +158,125 @@ pub fn read_opts_file(path: &str) -> Opts {
 fn parse_opts(input: &str) -> Opts {
     serde_yaml::from_str(input).unwrap()
 }
+
+fn default_cache_memory_size_mb() -> u64 {
+    64
+}
+
+fn default_cache_rules() -> Vec<CacheRule> {
+    vec![CacheRule {
+        path: default_cache_rule_path(),
+        content_types: default_cache_rule_content_types(),
+        methods: default_cache_rule_methods(),
+        status_codes: default_cache_rule_status_codes(),
+        ttl_secs: None,
+        max_size_mb: default_cache_rule_max_size_mb(),
+    }]
+}
+
+fn default_cache_rule_path() -> String {
+    "^/".to_string()
+}
+
+fn default_cache_rule_content_types() -> Vec<String> {
+    vec![
+        "application/octet-stream",
+        "text/css",
+        "text/javascript",
+        "application/javascript",
+        "image/apng",
+        "image/avif",
+        "image/gif",
+        "image/jpeg",
+        "image/png",
+        "image/svg+xml",
+        "image/webp",
+        "image/bmp",
+        "image/x-icon",
+        "image/tiff",
+        "audio/webm",
+        "audio/mpeg",
+        "audio/ogg",
+        "audio/x-wav",
+        "audio/mp4",
+        "application/ogg",
+        "application/pdf",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()
+}
+
+fn default_cache_rule_methods() -> Vec<String> {
+    vec!["GET".to_string()]
+}
+
+fn default_cache_rule_status_codes() -> Vec<u16> {
+    vec![200, 201, 202, 203, 204, 205]
+}
+
+fn default_cache_rule_max_size_mb() -> u64 {
+    30
+}
+
+fn default_failover_deadline_ms() -> u64 {
+    10000
+}
+
+fn default_keep_alive_timeout_secs() -> u64 {
+    5
+}
+
+fn default_keep_alive_max_requests() -> u32 {
+    100
+}
+
+fn default_max_request_body_size_mb() -> u64 {
+    100
+}
+
+fn default_upstream_max_idle() -> usize {
+    8
+}
+
+// Kept below the 5s keep-alive timeout of Node.js servers so that pooled
+// connections are dropped by the proxy before the backend closes them.
+fn default_upstream_idle_timeout_secs() -> u64 {
+    4
+}
+
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_status() -> u16 {
+    200
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    5
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_health_check_rise() -> u32 {
+    2
+}
+
+fn default_health_check_fall() -> u32 {
+    3
+}
+
+fn default_circuit_breaker_failure_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_admin_addr() -> String {
+    "127.0.0.1".to_string()
+}